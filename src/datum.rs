@@ -0,0 +1,194 @@
+//! Conversions between WGS-84 and the datums used by maps in mainland China.
+//!
+//! GCJ-02 is the obfuscated datum required for maps published in China, used by Amap (Gaode),
+//! Tencent and Google Maps in China. BD-09 is Baidu's further offset of GCJ-02. Like the
+//! reference algorithms, the offsets are only applied inside a bounding box of China, outside
+//! of it the three datums are the same.
+//!
+//! Only the WGS-84 to GCJ-02 and GCJ-02 to BD-09 directions have a closed form, the inverses are
+//! found by iterating on them and are precise to about a millimeter.
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Datum {
+    #[default]
+    Wgs84,
+    Gcj02,
+    Bd09,
+}
+
+impl Datum {
+    /// Converts a location in this datum to WGS-84.
+    pub fn to_wgs84(self, lng: f64, lat: f64) -> (f64, f64) {
+        match self {
+            Datum::Wgs84 => (lng, lat),
+            Datum::Gcj02 => gcj02_to_wgs84(lng, lat),
+            Datum::Bd09 => {
+                let (lng, lat) = bd09_to_gcj02(lng, lat);
+                gcj02_to_wgs84(lng, lat)
+            }
+        }
+    }
+
+    /// Converts a WGS-84 location to this datum.
+    pub fn from_wgs84(self, lng: f64, lat: f64) -> (f64, f64) {
+        match self {
+            Datum::Wgs84 => (lng, lat),
+            Datum::Gcj02 => wgs84_to_gcj02(lng, lat),
+            Datum::Bd09 => {
+                let (lng, lat) = wgs84_to_gcj02(lng, lat);
+                gcj02_to_bd09(lng, lat)
+            }
+        }
+    }
+
+    /// Converts a location in this datum to another one.
+    pub fn convert(self, to: Datum, lng: f64, lat: f64) -> (f64, f64) {
+        match (self, to) {
+            (Datum::Gcj02, Datum::Bd09) => gcj02_to_bd09(lng, lat),
+            (Datum::Bd09, Datum::Gcj02) => bd09_to_gcj02(lng, lat),
+            _ if self == to => (lng, lat),
+            _ => {
+                let (lng, lat) = self.to_wgs84(lng, lat);
+                to.from_wgs84(lng, lat)
+            }
+        }
+    }
+}
+
+// Krasovsky 1940 ellipsoid
+const A: f64 = 6378245.0;
+const EE: f64 = 0.006_693_421_622_965_943;
+const X_PI: f64 = PI * 3000.0 / 180.0;
+
+/// The rough bounding box of China used by the reference algorithms.
+pub fn out_of_china(lng: f64, lat: f64) -> bool {
+    !(72.004..=137.8347).contains(&lng) || !(0.8293..=55.8271).contains(&lat)
+}
+
+fn transform_lat(x: f64, y: f64) -> f64 {
+    let mut ret = -100.0 + 2.0 * x + 3.0 * y + 0.2 * y * y + 0.1 * x * y + 0.2 * x.abs().sqrt();
+    ret += (20.0 * (6.0 * x * PI).sin() + 20.0 * (2.0 * x * PI).sin()) * 2.0 / 3.0;
+    ret += (20.0 * (y * PI).sin() + 40.0 * (y / 3.0 * PI).sin()) * 2.0 / 3.0;
+    ret += (160.0 * (y / 12.0 * PI).sin() + 320.0 * (y * PI / 30.0).sin()) * 2.0 / 3.0;
+    ret
+}
+
+fn transform_lng(x: f64, y: f64) -> f64 {
+    let mut ret = 300.0 + x + 2.0 * y + 0.1 * x * x + 0.1 * x * y + 0.1 * x.abs().sqrt();
+    ret += (20.0 * (6.0 * x * PI).sin() + 20.0 * (2.0 * x * PI).sin()) * 2.0 / 3.0;
+    ret += (20.0 * (x * PI).sin() + 40.0 * (x / 3.0 * PI).sin()) * 2.0 / 3.0;
+    ret += (150.0 * (x / 12.0 * PI).sin() + 300.0 * (x / 30.0 * PI).sin()) * 2.0 / 3.0;
+    ret
+}
+
+pub fn wgs84_to_gcj02(lng: f64, lat: f64) -> (f64, f64) {
+    if out_of_china(lng, lat) {
+        return (lng, lat);
+    }
+    let rad_lat = lat.to_radians();
+    let magic = 1.0 - EE * rad_lat.sin() * rad_lat.sin();
+    let sqrt_magic = magic.sqrt();
+    let d_lat = transform_lat(lng - 105.0, lat - 35.0) * 180.0
+        / ((A * (1.0 - EE)) / (magic * sqrt_magic) * PI);
+    let d_lng =
+        transform_lng(lng - 105.0, lat - 35.0) * 180.0 / (A / sqrt_magic * rad_lat.cos() * PI);
+    (lng + d_lng, lat + d_lat)
+}
+
+pub fn gcj02_to_bd09(lng: f64, lat: f64) -> (f64, f64) {
+    if out_of_china(lng, lat) {
+        return (lng, lat);
+    }
+    let z = (lng * lng + lat * lat).sqrt() + 0.00002 * (lat * X_PI).sin();
+    let theta = lat.atan2(lng) + 0.000003 * (lng * X_PI).cos();
+    (z * theta.cos() + 0.0065, z * theta.sin() + 0.006)
+}
+
+// Finds where `forward` maps to the target, starting from an approximation.
+fn invert(
+    forward: fn(f64, f64) -> (f64, f64),
+    target: (f64, f64),
+    mut guess: (f64, f64),
+) -> (f64, f64) {
+    for _ in 0..30 {
+        let (lng, lat) = forward(guess.0, guess.1);
+        let (d_lng, d_lat) = (lng - target.0, lat - target.1);
+        guess = (guess.0 - d_lng, guess.1 - d_lat);
+        // about a millimeter
+        if d_lng.abs() < 1e-8 && d_lat.abs() < 1e-8 {
+            break;
+        }
+    }
+    guess
+}
+
+pub fn gcj02_to_wgs84(lng: f64, lat: f64) -> (f64, f64) {
+    if out_of_china(lng, lat) {
+        return (lng, lat);
+    }
+    invert(wgs84_to_gcj02, (lng, lat), (lng, lat))
+}
+
+pub fn bd09_to_gcj02(lng: f64, lat: f64) -> (f64, f64) {
+    if out_of_china(lng, lat) {
+        return (lng, lat);
+    }
+    // the approximate inverse of the reference algorithms, as a start
+    let (x, y) = (lng - 0.0065, lat - 0.006);
+    let z = (x * x + y * y).sqrt() - 0.00002 * (y * X_PI).sin();
+    let theta = y.atan2(x) - 0.000003 * (x * X_PI).cos();
+    invert(
+        gcj02_to_bd09,
+        (lng, lat),
+        (z * theta.cos(), z * theta.sin()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: (f64, f64), b: (f64, f64), tolerance: f64) {
+        assert!(
+            (a.0 - b.0).abs() < tolerance && (a.1 - b.1).abs() < tolerance,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        assert_close(
+            wgs84_to_gcj02(116.404, 39.915),
+            (116.41024449916938, 39.91640428150164),
+            1e-12,
+        );
+        assert_close(
+            gcj02_to_bd09(116.404, 39.915),
+            (116.41036949371029, 39.92133699351022),
+            1e-12,
+        );
+        // not shifted outside of China
+        assert_eq!(wgs84_to_gcj02(2.35, 48.85), (2.35, 48.85));
+        assert_eq!(Datum::Bd09.to_wgs84(-122.4, 37.8), (-122.4, 37.8));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let datums = [Datum::Wgs84, Datum::Gcj02, Datum::Bd09];
+        for &(lng, lat) in &[(121.4737, 31.2304), (113.2644, 23.1291), (87.6168, 43.8256)] {
+            for from in datums {
+                for to in datums {
+                    let (x, y) = from.convert(to, lng, lat);
+                    assert_close(to.convert(from, x, y), (lng, lat), 1e-8);
+                }
+            }
+            // the offsets are a few hundred meters
+            let (x, y) = Datum::Bd09.from_wgs84(lng, lat);
+            assert!((x - lng).abs() > 1e-3 || (y - lat).abs() > 1e-3);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Export of the visited cells as a table, in Apache Arrow IPC or Parquet.
+//!
+//! A row is written per visited cell of the chosen zoom level, with its centre, its area on the
+//! ground and the tile of the map holding it. The rows are written a tile of the map at a time,
+//! so that the table of a large map is never held in memory. Cells larger than the tiles of the
+//! map, below zoom [`TILE_ZOOM`], are gathered first, as they are few.
+
+use super::{cell_to_lng_lat, tile_cells, visited_cells, Bounds, PIXEL_ZOOM};
+use crate::fogmaps::ALL_OFFSET;
+use crate::FogMap;
+use arrow_array::{ArrayRef, Float64Array, Int16Array, Int64Array, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Arc;
+
+/// The zoom level of the tiles of the map.
+pub const TILE_ZOOM: i16 = PIXEL_ZOOM - ALL_OFFSET;
+
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ColumnarFormat {
+    /// The Arrow IPC file format, also known as Feather.
+    #[default]
+    ArrowIpc,
+    Parquet,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnarOptions {
+    /// The zoom level of the cells, at most [`PIXEL_ZOOM`].
+    pub zoom: i16,
+    pub format: ColumnarFormat,
+}
+
+impl Default for ColumnarOptions {
+    fn default() -> Self {
+        Self {
+            zoom: 16,
+            format: ColumnarFormat::ArrowIpc,
+        }
+    }
+}
+
+fn schema() -> SchemaRef {
+    let field = |name: &str, data_type: DataType| Field::new(name, data_type, false);
+    Arc::new(Schema::new(vec![
+        field("cell_x", DataType::Int64),
+        field("cell_y", DataType::Int64),
+        field("zoom", DataType::Int16),
+        field("lng", DataType::Float64),
+        field("lat", DataType::Float64),
+        field("area_m2", DataType::Float64),
+        field("tile_x", DataType::Int64),
+        field("tile_y", DataType::Int64),
+    ]))
+}
+
+// The writers of the formats, behind a single interface.
+enum TableWriter<W: Write + Send> {
+    ArrowIpc(FileWriter<W>),
+    Parquet(ArrowWriter<W>),
+}
+
+impl<W: Write + Send> TableWriter<W> {
+    fn new(writer: W, format: ColumnarFormat) -> Result<Self, String> {
+        let schema = schema();
+        Ok(match format {
+            ColumnarFormat::ArrowIpc => {
+                Self::ArrowIpc(FileWriter::try_new(writer, &schema).map_err(|e| e.to_string())?)
+            }
+            ColumnarFormat::Parquet => Self::Parquet(
+                ArrowWriter::try_new(writer, schema, None).map_err(|e| e.to_string())?,
+            ),
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), String> {
+        match self {
+            Self::ArrowIpc(writer) => writer.write(batch).map_err(|e| e.to_string()),
+            Self::Parquet(writer) => writer.write(batch).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            Self::ArrowIpc(mut writer) => writer.finish().map_err(|e| e.to_string()),
+            Self::Parquet(writer) => writer.close().map(|_| ()).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// A cell and the tile of the map holding it.
+type CellRow = ((i64, i64), (i64, i64));
+
+// The rows of sorted cells of `zoom` and the tiles of the map holding them.
+fn record_batch(zoom: i16, cells: &[CellRow]) -> RecordBatch {
+    let mut lngs = Vec::with_capacity(cells.len());
+    let mut lats = Vec::with_capacity(cells.len());
+    let mut areas = Vec::with_capacity(cells.len());
+    for &((x, y), _) in cells {
+        let (west, north) = cell_to_lng_lat(x as f64, y as f64, zoom);
+        let (east, south) = cell_to_lng_lat((x + 1) as f64, (y + 1) as f64, zoom);
+        let (lng, lat) = cell_to_lng_lat(x as f64 + 0.5, y as f64 + 0.5, zoom);
+        lngs.push(lng);
+        lats.push(lat);
+        areas.push(
+            EARTH_RADIUS
+                * EARTH_RADIUS
+                * (east - west).to_radians()
+                * (north.to_radians().sin() - south.to_radians().sin()),
+        );
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(cells.iter().map(|c| c.0 .0))),
+        Arc::new(Int64Array::from_iter_values(cells.iter().map(|c| c.0 .1))),
+        Arc::new(Int16Array::from(vec![zoom; cells.len()])),
+        Arc::new(Float64Array::from(lngs)),
+        Arc::new(Float64Array::from(lats)),
+        Arc::new(Float64Array::from(areas)),
+        Arc::new(Int64Array::from_iter_values(cells.iter().map(|c| c.1 .0))),
+        Arc::new(Int64Array::from_iter_values(cells.iter().map(|c| c.1 .1))),
+    ];
+    RecordBatch::try_new(schema(), columns).unwrap()
+}
+
+impl FogMap {
+    /// Writes the visited cells of a zoom level to `writer` as a table, returning the number of
+    /// rows. A cell larger than a tile of the map is listed with the tile at its corner.
+    pub fn export_cells<W: Write + Send>(
+        &self,
+        writer: W,
+        options: &ColumnarOptions,
+    ) -> Result<usize, String> {
+        let zoom = options.zoom;
+        if !(0..=PIXEL_ZOOM).contains(&zoom) {
+            return Err(format!("Invalid zoom {}", zoom));
+        }
+        let mut table = TableWriter::new(writer, options.format)?;
+        let mut rows = 0;
+
+        if zoom < TILE_ZOOM {
+            let (xs, ys) = Bounds::world().cell_ranges(zoom);
+            let mut cells: Vec<CellRow> = visited_cells(self, zoom, &xs, &ys)
+                .into_iter()
+                .map(|(x, y)| ((x, y), (x << (TILE_ZOOM - zoom), y << (TILE_ZOOM - zoom))))
+                .collect();
+            cells.sort_unstable();
+            if !cells.is_empty() {
+                table.write(&record_batch(zoom, &cells))?;
+            }
+            rows += cells.len();
+        } else {
+            let mut tiles: Vec<&(i64, i64)> = self.tiles.keys().collect();
+            tiles.sort_unstable();
+            for &(tile_x, tile_y) in tiles {
+                let mut cells = HashSet::new();
+                let tile = &self.tiles[&(tile_x, tile_y)];
+                tile_cells((tile_x, tile_y), tile, PIXEL_ZOOM - zoom, |x, y| {
+                    cells.insert((x, y));
+                });
+                let mut cells: Vec<CellRow> = cells
+                    .into_iter()
+                    .map(|cell| (cell, (tile_x, tile_y)))
+                    .collect();
+                cells.sort_unstable();
+                if !cells.is_empty() {
+                    table.write(&record_batch(zoom, &cells))?;
+                }
+                rows += cells.len();
+            }
+        }
+        table.finish()?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type};
+    use arrow_ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::io::Cursor;
+
+    fn read_ipc(data: Vec<u8>) -> Vec<RecordBatch> {
+        FileReader::try_new(Cursor::new(data), None)
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_export_cells() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+        fogmap.add_line(2.35, 48.85, 2.36, 48.86);
+
+        let mut data = Vec::new();
+        let options = ColumnarOptions {
+            zoom: 14,
+            format: ColumnarFormat::ArrowIpc,
+        };
+        let rows = fogmap.export_cells(&mut data, &options).unwrap();
+        let batches = read_ipc(data);
+        // a batch per tile of the map
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), rows);
+
+        let batch = batches
+            .iter()
+            .find(|batch| batch.column(3).as_primitive::<Float64Type>().value(0) > 100.0)
+            .unwrap();
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.40, 31.20, 14);
+        let cell_x = batch.column(0).as_primitive::<Int64Type>();
+        let cell_y = batch.column(1).as_primitive::<Int64Type>();
+        let i = (0..batch.num_rows())
+            .find(|&i| cell_x.value(i) == x && cell_y.value(i) == y)
+            .unwrap();
+        let lng = batch.column(3).as_primitive::<Float64Type>().value(i);
+        let lat = batch.column(4).as_primitive::<Float64Type>().value(i);
+        assert!((lng - 121.40).abs() < 0.03 && (lat - 31.20).abs() < 0.03);
+        // a cell of zoom 14 is about 2.1km wide at this latitude
+        let area = batch.column(5).as_primitive::<Float64Type>().value(i);
+        assert!((area / 1e6 - 2.08 * 2.08).abs() < 0.3, "{}", area);
+        let (tile_x, tile_y) = FogMap::lng_lat_to_tile_x_y(121.40, 31.20, TILE_ZOOM);
+        assert_eq!(batch.column(6).as_primitive::<Int64Type>().value(i), tile_x);
+        assert_eq!(batch.column(7).as_primitive::<Int64Type>().value(i), tile_y);
+
+        // coarse cells, in Parquet
+        let path =
+            std::env::temp_dir().join(format!("fogcore-cells-{}.parquet", std::process::id()));
+        let options = ColumnarOptions {
+            zoom: 4,
+            format: ColumnarFormat::Parquet,
+        };
+        let file = std::fs::File::create(&path).unwrap();
+        assert_eq!(fogmap.export_cells(file, &options).unwrap(), 2);
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+        assert_eq!(batches[0].num_rows(), 2);
+        assert_eq!(batches[0].schema(), schema());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
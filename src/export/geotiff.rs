@@ -0,0 +1,225 @@
+//! Export of the visited areas as a single band GeoTIFF in Web Mercator (EPSG:3857), for GIS
+//! tools.
+//!
+//! The raster has 256 pixels per tile of the chosen zoom level and covers the tiles overlapping
+//! the exported area. Each tile is an internal tile of the GeoTIFF, rasterized by [`TileShader`]
+//! and compressed with deflate.
+
+use super::{Bounds, PIXEL_ZOOM};
+use crate::renderer::TileShader;
+use crate::FogMap;
+use flate2::write::ZlibEncoder;
+use image::{Rgba, RgbaImage};
+use std::convert::TryFrom;
+use std::io::{Seek, Write};
+use tiff::encoder::TiffEncoder;
+use tiff::tags::Tag;
+
+/// The sample size of the raster.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum BitDepth {
+    /// 1 for the visited pixels, 0 for the others.
+    One,
+    /// 255 for the visited pixels, 0 for the others.
+    #[default]
+    Eight,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeoTiffOptions {
+    /// The zoom level of the tiles of the raster, at most [`PIXEL_ZOOM`] - 8.
+    pub zoom: i16,
+    /// The area exported, the extent of the map when unset.
+    pub bounds: Option<Bounds>,
+    pub bit_depth: BitDepth,
+}
+
+impl Default for GeoTiffOptions {
+    fn default() -> Self {
+        Self {
+            zoom: 12,
+            bounds: None,
+            bit_depth: BitDepth::Eight,
+        }
+    }
+}
+
+const TILE_SIZE_POWER: i16 = 8;
+const TILE_SIZE: u32 = 1 << TILE_SIZE_POWER;
+
+// half the circumference of the earth, in metres of EPSG:3857
+const HALF_CIRCUMFERENCE: f64 = 20_037_508.342_789_244;
+
+// values of the GeoTIFF tags
+const COMPRESSION_DEFLATE: u16 = 8;
+const PHOTOMETRIC_BLACK_IS_ZERO: u16 = 1;
+const GEO_KEYS: [u16; 16] = [
+    // version, revision, minor revision and number of keys
+    1, 1, 0, 3, //
+    // GTModelTypeGeoKey: projected
+    1024, 0, 1, 1, //
+    // GTRasterTypeGeoKey: pixel is area
+    1025, 0, 1, 1, //
+    // ProjectedCSTypeGeoKey: Web Mercator
+    3072, 0, 1, 3857,
+];
+
+impl FogMap {
+    /// Rasterizes the visited areas into `writer` as a GeoTIFF.
+    pub fn export_geotiff<W: Write + Seek>(
+        &self,
+        writer: W,
+        options: &GeoTiffOptions,
+    ) -> Result<(), String> {
+        let zoom = options.zoom;
+        if zoom < 0 || zoom + TILE_SIZE_POWER > PIXEL_ZOOM {
+            return Err(format!("Invalid zoom {}", zoom));
+        }
+        let bounds = options
+            .bounds
+            .or_else(|| self.bounds())
+            .ok_or("Nothing to export in an empty map")?;
+        let n = 1i64 << zoom;
+        let (xs, ys) = bounds.cell_ranges(zoom);
+        if xs.is_empty() || ys.is_empty() {
+            return Err("Nothing to export in empty bounds".to_string());
+        }
+        let columns = (xs.end() - xs.start() + 1) as u32;
+        let rows = (ys.end() - ys.start() + 1) as u32;
+
+        let tiff_error = |e: tiff::TiffError| format!("Failed to write the GeoTIFF: {}", e);
+        let too_large = |_| "The GeoTIFF is too large".to_string();
+        let mut encoder = TiffEncoder::new(writer).map_err(tiff_error)?;
+        let mut directory = encoder.image_directory().map_err(tiff_error)?;
+
+        let mut offsets: Vec<u32> = Vec::new();
+        let mut byte_counts: Vec<u32> = Vec::new();
+        let mut image = RgbaImage::new(TILE_SIZE, TILE_SIZE);
+        for y in ys.clone() {
+            for x in xs.clone() {
+                TileShader::render_on_image(
+                    &mut image,
+                    0,
+                    0,
+                    self,
+                    x.rem_euclid(n),
+                    y,
+                    zoom,
+                    TILE_SIZE_POWER,
+                    Rgba([0, 0, 0, 0]),
+                    Rgba([255, 255, 255, 255]),
+                );
+                let samples: Vec<u8> = match options.bit_depth {
+                    BitDepth::Eight => image.pixels().map(|pixel| pixel.0[0]).collect(),
+                    BitDepth::One => {
+                        // the rows are packed from the most significant bit
+                        let mut samples = vec![0u8; (TILE_SIZE * TILE_SIZE / 8) as usize];
+                        for (i, pixel) in image.pixels().enumerate() {
+                            if pixel.0[0] > 0 {
+                                samples[i / 8] |= 0x80 >> (i % 8);
+                            }
+                        }
+                        samples
+                    }
+                };
+                let mut compressor = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                compressor.write_all(&samples).unwrap();
+                let compressed = compressor.finish().unwrap();
+
+                let offset = directory
+                    .write_data(compressed.as_slice())
+                    .map_err(tiff_error)?;
+                offsets.push(u32::try_from(offset).map_err(too_large)?);
+                byte_counts.push(compressed.len() as u32);
+            }
+        }
+
+        let bits_per_sample: u16 = match options.bit_depth {
+            BitDepth::One => 1,
+            BitDepth::Eight => 8,
+        };
+        let resolution = 2.0 * HALF_CIRCUMFERENCE / ((n as f64) * TILE_SIZE as f64);
+        let west = *xs.start() as f64 * TILE_SIZE as f64 * resolution - HALF_CIRCUMFERENCE;
+        let north = HALF_CIRCUMFERENCE - *ys.start() as f64 * TILE_SIZE as f64 * resolution;
+        let mut write_tags = || -> tiff::TiffResult<()> {
+            directory.write_tag(Tag::ImageWidth, columns * TILE_SIZE)?;
+            directory.write_tag(Tag::ImageLength, rows * TILE_SIZE)?;
+            directory.write_tag(Tag::BitsPerSample, bits_per_sample)?;
+            directory.write_tag(Tag::Compression, COMPRESSION_DEFLATE)?;
+            directory.write_tag(Tag::PhotometricInterpretation, PHOTOMETRIC_BLACK_IS_ZERO)?;
+            directory.write_tag(Tag::SamplesPerPixel, 1u16)?;
+            directory.write_tag(Tag::TileWidth, TILE_SIZE)?;
+            directory.write_tag(Tag::TileLength, TILE_SIZE)?;
+            directory.write_tag(Tag::TileOffsets, offsets.as_slice())?;
+            directory.write_tag(Tag::TileByteCounts, byte_counts.as_slice())?;
+            directory.write_tag(Tag::ModelPixelScaleTag, &[resolution, resolution, 0.0][..])?;
+            directory.write_tag(
+                Tag::ModelTiepointTag,
+                &[0.0, 0.0, 0.0, west, north, 0.0][..],
+            )?;
+            directory.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEYS[..])
+        };
+        write_tags().map_err(tiff_error)?;
+        directory.finish().map_err(tiff_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    #[test]
+    fn test_export_geotiff() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+        let bounds = fogmap.bounds().unwrap();
+        let zoom = 12;
+
+        for bit_depth in [BitDepth::Eight, BitDepth::One] {
+            let mut data = Cursor::new(Vec::new());
+            let options = GeoTiffOptions {
+                zoom,
+                bounds: None,
+                bit_depth,
+            };
+            fogmap.export_geotiff(&mut data, &options).unwrap();
+
+            data.set_position(0);
+            let mut decoder = Decoder::new(data).unwrap();
+            let (xs, ys) = bounds.cell_ranges(zoom);
+            let (width, height) = decoder.dimensions().unwrap();
+            assert_eq!(width as i64, (xs.end() - xs.start() + 1) * 256);
+            assert_eq!(height as i64, (ys.end() - ys.start() + 1) * 256);
+
+            let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap();
+            assert!((scale[0] - 2.0 * HALF_CIRCUMFERENCE / (1 << 20) as f64).abs() < 1e-9);
+            let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+            // the corner of the raster is at most a tile away from the line
+            let x = 121.40f64.to_radians() * 6_378_137.0;
+            assert!(tiepoint[3] <= x && x - tiepoint[3] < 256.0 * scale[0]);
+            let geo_keys = decoder.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).unwrap();
+            assert_eq!(geo_keys[geo_keys.len() - 1], 3857);
+
+            // the pixel of the start of the line, and a pixel away from it
+            let (px, py) = FogMap::lng_lat_to_tile_x_y(121.4001, 31.2001, zoom + 8);
+            let (px, py) = (
+                (px - xs.start() * 256) as usize,
+                (py - ys.start() * 256) as usize,
+            );
+            let DecodingResult::U8(samples) = decoder.read_image().unwrap() else {
+                panic!("unexpected samples");
+            };
+            let visited = |x: usize, y: usize| match bit_depth {
+                BitDepth::Eight => samples[y * width as usize + x] == 255,
+                BitDepth::One => {
+                    let i = y * width as usize + x;
+                    samples[i / 8] & (0x80 >> (i % 8)) != 0
+                }
+            };
+            assert!(visited(px, py) || visited(px + 1, py) || visited(px, py + 1));
+            assert!(!visited(px, py + 100));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Conversions of the visited areas to the cells of discrete global grids, H3 and geohash.
+//!
+//! A cell is listed when the visited pixels within it cover at least a fraction of its area on
+//! the ground, a pixel being within the cell holding its centre. With a fraction of 0, every
+//! cell holding a visited pixel is listed. See [`FogMap::add_h3_cells`] for the reverse.
+
+use super::{cell_to_lng_lat, visited_pixels, PIXEL_ZOOM};
+use crate::FogMap;
+use geohash::Coord;
+use h3o::{CellIndex, LatLng, Resolution};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::hash::Hash;
+
+/// The longest geohash.
+pub const MAX_GEOHASH_PRECISION: usize = 12;
+
+// The area of a spherical box in degrees, on the unit sphere.
+fn box_area(west: f64, south: f64, east: f64, north: f64) -> f64 {
+    (east - west).to_radians() * (north.to_radians().sin() - south.to_radians().sin())
+}
+
+// The cells holding the visited pixels and covered enough, with `cell_of` the cell holding a
+// location and `area_of` the area of a cell on the unit sphere.
+fn covered_cells<K: Hash + Eq>(
+    fogmap: &FogMap,
+    min_coverage: f64,
+    mut cell_of: impl FnMut(f64, f64) -> K,
+    area_of: impl Fn(&K) -> f64,
+) -> Vec<K> {
+    // a pixel is a square in Web Mercator, shrinking with the cosine of its latitude
+    let pixel_width = 2.0 * PI / (1i64 << PIXEL_ZOOM) as f64;
+    let mut covered: HashMap<K, f64> = HashMap::new();
+    // the y, latitude and area of the pixels of the current row
+    let mut row = (i64::MIN, 0.0, 0.0);
+    for (x, y) in visited_pixels(fogmap) {
+        if y != row.0 {
+            let (_, lat) = cell_to_lng_lat(0.0, y as f64 + 0.5, PIXEL_ZOOM);
+            row = (y, lat, (pixel_width * lat.to_radians().cos()).powi(2));
+        }
+        let (lng, _) = cell_to_lng_lat(x as f64 + 0.5, 0.0, PIXEL_ZOOM);
+        *covered.entry(cell_of(lng, row.1)).or_default() += row.2;
+    }
+    covered
+        .into_iter()
+        .filter(|(cell, area)| min_coverage <= 0.0 || *area >= min_coverage * area_of(cell))
+        .map(|(cell, _)| cell)
+        .collect()
+}
+
+impl FogMap {
+    /// The H3 cells of `resolution` covered by the visited areas, sorted by index.
+    pub fn to_h3_cells(&self, resolution: u8, min_coverage: f64) -> Result<Vec<CellIndex>, String> {
+        let resolution = Resolution::try_from(resolution).map_err(|e| e.to_string())?;
+        let mut cells = covered_cells(
+            self,
+            min_coverage,
+            |lng, lat| LatLng::new(lat, lng).unwrap().to_cell(resolution),
+            |cell| cell.area_rads2(),
+        );
+        cells.sort_unstable_by_key(|&cell| u64::from(cell));
+        Ok(cells)
+    }
+
+    /// The geohashes of `precision` characters covered by the visited areas, sorted.
+    pub fn to_geohashes(&self, precision: usize, min_coverage: f64) -> Result<Vec<String>, String> {
+        if !(1..=MAX_GEOHASH_PRECISION).contains(&precision) {
+            return Err(format!("Invalid geohash precision {}", precision));
+        }
+        let mut hashes = covered_cells(
+            self,
+            min_coverage,
+            |lng, lat| geohash::encode(Coord { x: lng, y: lat }, precision).unwrap(),
+            |hash| {
+                let rect = geohash::decode_bbox(hash).unwrap();
+                box_area(rect.min().x, rect.min().y, rect.max().x, rect.max().y)
+            },
+        );
+        hashes.sort_unstable();
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_cells() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+
+        let cells = fogmap.to_h3_cells(7, 0.0).unwrap();
+        assert!(cells.len() >= 2);
+        let start = LatLng::new(31.20, 121.40)
+            .unwrap()
+            .to_cell(Resolution::Seven);
+        assert!(cells.contains(&start));
+        // a line covers little of a cell
+        assert!(fogmap.to_h3_cells(7, 0.5).unwrap().is_empty());
+        assert!(fogmap.to_h3_cells(16, 0.0).is_err());
+
+        let hashes = fogmap.to_geohashes(5, 0.0).unwrap();
+        assert!(hashes.contains(
+            &geohash::encode(
+                Coord {
+                    x: 121.40,
+                    y: 31.20
+                },
+                5
+            )
+            .unwrap()
+        ));
+        assert!(hashes.iter().all(|hash| hash.starts_with("wtw")));
+        assert_eq!(fogmap.to_geohashes(1, 0.0).unwrap(), vec!["w"]);
+        assert!(fogmap.to_geohashes(13, 0.0).is_err());
+    }
+}
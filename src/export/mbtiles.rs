@@ -0,0 +1,323 @@
+//! Export of rendered tiles to an MBTiles file, for offline maps.
+//!
+//! Only the tiles holding visited areas are rendered, the others being left to the background
+//! of the map. Tiles already in the file are kept when it was written from the same map with the
+//! same options, so an interrupted export is resumed by running it again. Otherwise they are
+//! removed and rendered again, so that the tiles of a map that has changed since are not kept.
+
+use super::{zoom_tiles, TileRange};
+use crate::renderer::TileRendererTrait;
+use crate::utils::{image_to_png_data, DEFAULT_BG_COLOR2, DEFAULT_FG_COLOR2};
+use crate::FogMap;
+use image::Rgba;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct MbtilesOptions {
+    pub range: TileRange,
+    pub bg_color: Rgba<u8>,
+    pub fg_color: Rgba<u8>,
+    /// The `name` of the tileset in the metadata.
+    pub name: String,
+    /// Identifies the renderer, so that tiles rendered by another one are not resumed.
+    pub renderer: String,
+}
+
+impl Default for MbtilesOptions {
+    fn default() -> Self {
+        Self {
+            range: TileRange::default(),
+            bg_color: DEFAULT_BG_COLOR2,
+            fg_color: DEFAULT_FG_COLOR2,
+            name: "fog".to_string(),
+            renderer: String::new(),
+        }
+    }
+}
+
+/// What an export has written.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MbtilesSummary {
+    /// Number of tiles rendered and written.
+    pub written: usize,
+    /// Number of tiles already in the file, from a previous run.
+    pub existing: usize,
+    /// Whether the tiles of a previous run were removed, the map or the options having changed.
+    pub replaced: bool,
+}
+
+// Commits every this many tiles, which is what a resumed export starts from.
+const BATCH_SIZE: usize = 256;
+
+// The metadata recording what the tiles were rendered from.
+const EXPORT_METADATA: &str = "fogcore_export";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS metadata (name TEXT NOT NULL, value TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS metadata_name ON metadata (name);
+    CREATE TABLE IF NOT EXISTS tiles (
+        zoom_level INTEGER NOT NULL,
+        tile_column INTEGER NOT NULL,
+        tile_row INTEGER NOT NULL,
+        tile_data BLOB NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+// FNV-1a, which is written out here so that the fingerprints stored in files stay the same
+// across builds.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.write(&value.to_le_bytes());
+    }
+}
+
+// Identifies the map and the options of an export, so that a run resumes only the same export.
+fn export_fingerprint(
+    fogmap: &FogMap,
+    renderer: &dyn TileRendererTrait,
+    options: &MbtilesOptions,
+) -> String {
+    let mut hasher = Fnv::new();
+    let mut tiles: Vec<_> = fogmap.tiles.iter().collect();
+    tiles.sort_unstable_by_key(|(&key, _)| key);
+    for (&(tile_x, tile_y), tile) in tiles {
+        hasher.write_i64(tile_x);
+        hasher.write_i64(tile_y);
+        for ((block_x, block_y), block) in tile.blocks() {
+            hasher.write_i64(block_x);
+            hasher.write_i64(block_y);
+            hasher.write(block.bitmap());
+        }
+    }
+    format!(
+        "{:016x} {} {:?} {:?} {:?} {:?}",
+        hasher.0,
+        options.renderer,
+        renderer.get_tile_size(),
+        options.range,
+        options.bg_color,
+        options.fg_color
+    )
+}
+
+impl FogMap {
+    /// Renders the tiles of a zoom range with `renderer` into the MBTiles file at `path`,
+    /// created when missing.
+    pub fn export_mbtiles(
+        &self,
+        path: &Path,
+        renderer: &dyn TileRendererTrait,
+        options: &MbtilesOptions,
+    ) -> Result<MbtilesSummary, String> {
+        let bounds = options.range.area(self)?;
+        let db_error = |e: rusqlite::Error| format!("Failed to write {}: {}", path.display(), e);
+        let mut connection = Connection::open(path).map_err(db_error)?;
+        connection.execute_batch(SCHEMA).map_err(db_error)?;
+
+        let mut summary = MbtilesSummary::default();
+        let fingerprint = export_fingerprint(self, renderer, options);
+        let previous: Option<Option<String>> = connection
+            .query_row(
+                "SELECT value FROM metadata WHERE name = ?1",
+                [EXPORT_METADATA],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)?;
+        if previous.flatten().as_deref() != Some(fingerprint.as_str()) {
+            summary.replaced = connection
+                .execute("DELETE FROM tiles", [])
+                .map_err(db_error)?
+                > 0;
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                    params![EXPORT_METADATA, fingerprint],
+                )
+                .map_err(db_error)?;
+        }
+        let metadata = [
+            ("name", options.name.clone()),
+            ("format", "png".to_string()),
+            ("type", "overlay".to_string()),
+            ("version", "1".to_string()),
+            (
+                "bounds",
+                format!(
+                    "{},{},{},{}",
+                    bounds.west, bounds.south, bounds.east, bounds.north
+                ),
+            ),
+            ("minzoom", options.range.min_zoom.to_string()),
+            ("maxzoom", options.range.max_zoom.to_string()),
+        ];
+        for (name, value) in metadata {
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                    params![name, value],
+                )
+                .map_err(db_error)?;
+        }
+
+        for zoom in options.range.min_zoom..=options.range.max_zoom {
+            let n = 1i64 << zoom;
+            let tiles = zoom_tiles(self, zoom, &bounds, false);
+            for batch in tiles.chunks(BATCH_SIZE) {
+                let transaction = connection.transaction().map_err(db_error)?;
+                for &(x, y, _) in batch {
+                    // MBTiles numbers the rows from the south, as TMS does
+                    let row = n - 1 - y;
+                    let existing = transaction
+                        .query_row(
+                            "SELECT 1 FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 \
+                             AND tile_row = ?3",
+                            params![zoom, x, row],
+                            |_| Ok(()),
+                        )
+                        .optional()
+                        .map_err(db_error)?;
+                    if existing.is_some() {
+                        summary.existing += 1;
+                        continue;
+                    }
+                    let image =
+                        renderer.render_image(self, x, y, zoom, options.bg_color, options.fg_color);
+                    transaction
+                        .execute(
+                            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) \
+                             VALUES (?1, ?2, ?3, ?4)",
+                            params![zoom, x, row, image_to_png_data(&image)],
+                        )
+                        .map_err(db_error)?;
+                    summary.written += 1;
+                }
+                transaction.commit().map_err(db_error)?;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::TileRendererBasic;
+    use crate::utils::TileSize;
+
+    #[test]
+    fn test_fnv() {
+        let mut hasher = Fnv::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_export_mbtiles() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+        let path =
+            std::env::temp_dir().join(format!("fogcore-export-{}.mbtiles", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let renderer = TileRendererBasic::new(TileSize::TileSize256);
+        let options = MbtilesOptions {
+            range: TileRange {
+                min_zoom: 10,
+                max_zoom: 12,
+                bounds: None,
+            },
+            ..Default::default()
+        };
+
+        let summary = fogmap.export_mbtiles(&path, &renderer, &options).unwrap();
+        // a tile at 10, and two at 11 and 12 where the line crosses the edge of a tile
+        assert_eq!(summary.written, 5);
+        assert_eq!(summary.existing, 0);
+
+        let connection = Connection::open(&path).unwrap();
+        let metadata = |name: &str| -> String {
+            connection
+                .query_row(
+                    "SELECT value FROM metadata WHERE name = ?1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(metadata("format"), "png");
+        assert_eq!(metadata("minzoom"), "10");
+        let bounds: Vec<f64> = metadata("bounds")
+            .split(',')
+            .map(|value| value.parse().unwrap())
+            .collect();
+        assert!(bounds[0] < 121.40 && bounds[2] > 121.44);
+        assert!(bounds[1] < 31.20 && bounds[3] > 31.21);
+
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.40, 31.20, 10);
+        let data: Vec<u8> = connection
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = 10 AND tile_column = ?1 \
+                 AND tile_row = ?2",
+                params![x, (1 << 10) - 1 - y],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(data.starts_with(b"\x89PNG"));
+
+        // an export run again resumes, with nothing left to render
+        let summary = fogmap.export_mbtiles(&path, &renderer, &options).unwrap();
+        assert_eq!(summary.written, 0);
+        assert_eq!(summary.existing, 5);
+        assert!(!summary.replaced);
+
+        // the tiles of a map that has changed are rendered again, as are those of other options
+        fogmap.add_line(121.40, 31.30, 121.44, 31.31);
+        let summary = fogmap.export_mbtiles(&path, &renderer, &options).unwrap();
+        assert!(summary.replaced);
+        assert_eq!(summary.existing, 0);
+        assert!(summary.written > 5);
+        let options = MbtilesOptions {
+            fg_color: Rgba([255, 0, 0, 255]),
+            ..options
+        };
+        let summary = fogmap.export_mbtiles(&path, &renderer, &options).unwrap();
+        assert!(summary.replaced);
+        assert_eq!(summary.existing, 0);
+        let count: i64 = connection
+            .query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count as usize, summary.written);
+        let options = MbtilesOptions {
+            renderer: "premium".to_string(),
+            ..options
+        };
+        assert!(
+            fogmap
+                .export_mbtiles(&path, &renderer, &options)
+                .unwrap()
+                .replaced
+        );
+        let summary = fogmap.export_mbtiles(&path, &renderer, &options).unwrap();
+        assert!(!summary.replaced && summary.written == 0);
+        assert!(FogMap::new()
+            .export_mbtiles(&path, &renderer, &options)
+            .is_err());
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Exports of a [`FogMap`] to formats read by other tools.
+//!
+//! The exports work on square cells of a chosen zoom level, in the same tile addressing as the
+//! renderers. A cell is visited when any pixel of the map within it is. The pixels of the map
+//! are the cells of zoom [`PIXEL_ZOOM`], the finest level an export can use.
+
+#[cfg(feature = "native")]
+pub mod columnar;
+pub mod geotiff;
+pub mod grid;
+#[cfg(feature = "native")]
+pub mod mbtiles;
+pub mod mvt;
+pub mod pmtiles;
+pub mod polygons;
+#[cfg(feature = "native")]
+pub mod xyz;
+
+use crate::fogmaps::{
+    Block, Tile, ALL_OFFSET, BITMAP_WIDTH_OFFSET, MAP_WIDTH_OFFSET, TILE_WIDTH_OFFSET,
+};
+use crate::FogMap;
+use std::collections::HashSet;
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+
+/// The zoom level of the pixels of the map.
+pub const PIXEL_ZOOM: i16 = ALL_OFFSET + MAP_WIDTH_OFFSET;
+
+// the latitude where Web Mercator is square
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// An area in degrees. A box crossing the antimeridian has `east < west`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl Bounds {
+    /// The whole map.
+    pub fn world() -> Self {
+        Self {
+            west: -180.0,
+            south: -MAX_LATITUDE,
+            east: 180.0,
+            north: MAX_LATITUDE,
+        }
+    }
+
+    // The cells of `zoom` within the bounds, as inclusive ranges of x and y. The x range goes
+    // past the antimeridian rather than wrapping around it.
+    pub(crate) fn cell_ranges(&self, zoom: i16) -> (RangeInclusive<i64>, RangeInclusive<i64>) {
+        let n = 1i64 << zoom;
+        let mul = n as f64;
+        let x = |lng: f64| (lng.clamp(-180.0, 180.0) + 180.0) / 360.0 * mul;
+        let y = |lat: f64| {
+            let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+            (PI - lat.tan().asinh()) * mul / (2.0 * PI)
+        };
+        let x_start = (x(self.west).floor() as i64).min(n - 1);
+        let mut x_end = x(self.east).ceil() as i64 - 1;
+        if self.east < self.west {
+            x_end += n;
+        }
+        let y_start = (y(self.north).floor() as i64).clamp(0, n - 1);
+        let y_end = (y(self.south).ceil() as i64 - 1).clamp(0, n - 1);
+        (x_start..=x_end, y_start..=y_end)
+    }
+}
+
+/// The zoom levels and the area of an export of rendered tiles.
+#[derive(Debug, Clone)]
+pub struct TileRange {
+    pub min_zoom: i16,
+    /// At most [`PIXEL_ZOOM`], the resolution of the map.
+    pub max_zoom: i16,
+    /// The area exported, the extent of the map when unset.
+    pub bounds: Option<Bounds>,
+}
+
+impl Default for TileRange {
+    fn default() -> Self {
+        Self {
+            min_zoom: 0,
+            max_zoom: 14,
+            bounds: None,
+        }
+    }
+}
+
+impl TileRange {
+    // The area exported, after checking the zoom levels. An empty map has no area to export.
+    pub(crate) fn area(&self, fogmap: &FogMap) -> Result<Bounds, String> {
+        if self.max_zoom > PIXEL_ZOOM || self.min_zoom < 0 {
+            return Err(format!(
+                "Invalid zoom range {}-{}",
+                self.min_zoom, self.max_zoom
+            ));
+        }
+        self.bounds
+            .or_else(|| fogmap.bounds())
+            .ok_or_else(|| "Nothing to export in an empty map".to_string())
+    }
+}
+
+// The tiles of `zoom` within `bounds`, sorted and with the x wrapped around the antimeridian, as
+// `(x, y, visited)`. The tiles without visited areas are listed only with `all`.
+pub(crate) fn zoom_tiles(
+    fogmap: &FogMap,
+    zoom: i16,
+    bounds: &Bounds,
+    all: bool,
+) -> Vec<(i64, i64, bool)> {
+    let n = 1i64 << zoom;
+    let (xs, ys) = bounds.cell_ranges(zoom);
+    let visited: HashSet<(i64, i64)> = visited_cells(fogmap, zoom, &xs, &ys)
+        .into_iter()
+        .map(|(x, y)| (x.rem_euclid(n), y))
+        .collect();
+    let mut tiles: Vec<(i64, i64)> = if all {
+        xs.flat_map(|x| ys.clone().map(move |y| (x.rem_euclid(n), y)))
+            .collect()
+    } else {
+        visited.iter().copied().collect()
+    };
+    tiles.sort_unstable();
+    tiles.dedup();
+    tiles
+        .into_iter()
+        .map(|(x, y)| (x, y, visited.contains(&(x, y))))
+        .collect()
+}
+
+/// The corner of a cell of `zoom` in degrees, as `(lng, lat)`.
+pub(crate) fn cell_to_lng_lat(x: f64, y: f64, zoom: i16) -> (f64, f64) {
+    let mul = (1i64 << zoom) as f64;
+    let lng = x / mul * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * y / mul)).sinh().atan().to_degrees();
+    (lng, lat)
+}
+
+// The blocks of a tile of the map, with the pixel of the map at their corner.
+fn tile_blocks(
+    (tile_x, tile_y): (i64, i64),
+    tile: &Tile,
+) -> impl Iterator<Item = ((i64, i64), &Block)> {
+    tile.blocks().map(move |((block_x, block_y), block)| {
+        let x0 = ((tile_x << TILE_WIDTH_OFFSET) + block_x) << BITMAP_WIDTH_OFFSET;
+        let y0 = ((tile_y << TILE_WIDTH_OFFSET) + block_y) << BITMAP_WIDTH_OFFSET;
+        ((x0, y0), block)
+    })
+}
+
+// The visited pixels of a block with the pixel `(x0, y0)` at its corner, row by row.
+fn block_pixels((x0, y0): (i64, i64), block: &Block) -> impl Iterator<Item = (i64, i64)> + '_ {
+    (0..1 << BITMAP_WIDTH_OFFSET).flat_map(move |j| {
+        (0..1 << BITMAP_WIDTH_OFFSET)
+            .filter(move |&i| block.is_visited(i, j))
+            .map(move |i| (x0 + i, y0 + j))
+    })
+}
+
+/// The visited pixels of the map, row by row within each block.
+pub(crate) fn visited_pixels(fogmap: &FogMap) -> impl Iterator<Item = (i64, i64)> + '_ {
+    fogmap
+        .tiles
+        .iter()
+        .flat_map(|(&key, tile)| tile_blocks(key, tile))
+        .flat_map(|(origin, block)| block_pixels(origin, block))
+}
+
+// Calls `visit` with the cells holding the visited pixels of a tile of the map, at `shift` zoom
+// levels above the pixels. A cell may be visited more than once.
+pub(crate) fn tile_cells(
+    key: (i64, i64),
+    tile: &Tile,
+    shift: i16,
+    mut visit: impl FnMut(i64, i64),
+) {
+    for ((x0, y0), block) in tile_blocks(key, tile) {
+        if shift >= BITMAP_WIDTH_OFFSET {
+            // the block is within a single cell
+            if block.count_visited() > 0 {
+                visit(x0 >> shift, y0 >> shift);
+            }
+            continue;
+        }
+        for (x, y) in block_pixels((x0, y0), block) {
+            visit(x >> shift, y >> shift);
+        }
+    }
+}
+
+/// The visited cells of `zoom`, which is at most [`PIXEL_ZOOM`], within the ranges.
+pub(crate) fn visited_cells(
+    fogmap: &FogMap,
+    zoom: i16,
+    xs: &RangeInclusive<i64>,
+    ys: &RangeInclusive<i64>,
+) -> HashSet<(i64, i64)> {
+    let shift = PIXEL_ZOOM - zoom;
+    let n = 1i64 << zoom;
+    // the x of a cell within the range, trying the copies of the map across the antimeridian
+    let in_range = |x: i64| [x, x + n, x - n].iter().copied().find(|x| xs.contains(x));
+    let overlaps = |start: i64, end: i64, range: &RangeInclusive<i64>| {
+        start <= *range.end() && end >= *range.start()
+    };
+
+    let mut cells = HashSet::new();
+    for (&key, tile) in &fogmap.tiles {
+        let (tile_x, tile_y) = (key.0 << ALL_OFFSET, key.1 << ALL_OFFSET);
+        let tile_end = (1 << ALL_OFFSET) - 1;
+        let (x_start, x_end) = (tile_x >> shift, (tile_x + tile_end) >> shift);
+        if !overlaps(tile_y >> shift, (tile_y + tile_end) >> shift, ys)
+            || ![0, n, -n]
+                .iter()
+                .any(|offset| overlaps(x_start + offset, x_end + offset, xs))
+        {
+            continue;
+        }
+        tile_cells(key, tile, shift, |x, y| {
+            if let (Some(x), true) = (in_range(x), ys.contains(&y)) {
+                cells.insert((x, y));
+            }
+        });
+    }
+    cells
+}
+
+impl FogMap {
+    /// The smallest area holding every visited block of the map, `None` when nothing is visited.
+    pub fn bounds(&self) -> Option<Bounds> {
+        // blocks are the cells of this zoom
+        const BLOCK_ZOOM: i16 = PIXEL_ZOOM - BITMAP_WIDTH_OFFSET;
+        let mut range: Option<(i64, i64, i64, i64)> = None;
+        for (&(tile_x, tile_y), tile) in &self.tiles {
+            for ((block_x, block_y), block) in tile.blocks() {
+                if block.count_visited() == 0 {
+                    continue;
+                }
+                let x = (tile_x << TILE_WIDTH_OFFSET) + block_x;
+                let y = (tile_y << TILE_WIDTH_OFFSET) + block_y;
+                range = Some(match range {
+                    Some((min_x, min_y, max_x, max_y)) => {
+                        (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+                    }
+                    None => (x, y, x, y),
+                });
+            }
+        }
+        let (min_x, min_y, max_x, max_y) = range?;
+        let (west, north) = cell_to_lng_lat(min_x as f64, min_y as f64, BLOCK_ZOOM);
+        let (east, south) = cell_to_lng_lat((max_x + 1) as f64, (max_y + 1) as f64, BLOCK_ZOOM);
+        Some(Bounds {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_ranges() {
+        let bounds = |west: f64, east: f64| Bounds {
+            west,
+            south: 31.1,
+            east,
+            north: 31.3,
+        };
+        assert_eq!(Bounds::world().cell_ranges(2).0, 0..=3);
+        assert_eq!(bounds(-180.0, 0.0).cell_ranges(2).0, 0..=1);
+        // across the antimeridian
+        assert_eq!(bounds(90.0, -90.0).cell_ranges(2).0, 3..=4);
+        // a box without width is a single column, or nothing on the edge of a cell
+        assert_eq!(bounds(100.0, 100.0).cell_ranges(2).0, 3..=3);
+        assert!(bounds(0.0, 0.0).cell_ranges(2).0.is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! Mapbox Vector Tiles of the visited areas, for clients styling the fog themselves.
+//!
+//! A tile has a single layer, `explored`, holding the visited areas as one multipolygon feature.
+//! The polygons are traced like [`FogMap::to_polygons`], at the finest zoom level whose cells are
+//! no smaller than a unit of the tile, and clipped to the tile with a buffer around it.
+
+use super::polygons::{group_rings, trace_rings};
+use super::{visited_cells, PIXEL_ZOOM};
+use crate::FogMap;
+
+/// Name of the layer of the visited areas.
+pub const LAYER_NAME: &str = "explored";
+
+/// The buffer around the tile, as a fraction of its extent, so that the edges of neighbouring
+/// tiles do not show.
+const BUFFER_FRACTION: u32 = 64;
+
+// wire types
+const VARINT: u32 = 0;
+const LENGTH_DELIMITED: u32 = 2;
+
+// A protocol buffers message being written.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, VARINT);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, LENGTH_DELIMITED);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn packed(&mut self, field: u32, values: &[u32]) {
+        let mut packed = Message::default();
+        for &value in values {
+            packed.varint(value as u64);
+        }
+        self.bytes(field, &packed.0);
+    }
+}
+
+// Geometry commands and their parameters, see the `geometry` field of the specification.
+#[derive(Default)]
+struct Geometry {
+    commands: Vec<u32>,
+    cursor: (i64, i64),
+}
+
+impl Geometry {
+    const MOVE_TO: u32 = 1;
+    const LINE_TO: u32 = 2;
+    const CLOSE_PATH: u32 = 7;
+
+    fn command(&mut self, id: u32, count: usize) {
+        self.commands.push(id | ((count as u32) << 3));
+    }
+
+    fn point(&mut self, (x, y): (i64, i64)) {
+        let zigzag = |value: i64| ((value << 1) ^ (value >> 63)) as u32;
+        self.commands.push(zigzag(x - self.cursor.0));
+        self.commands.push(zigzag(y - self.cursor.1));
+        self.cursor = (x, y);
+    }
+
+    fn ring(&mut self, ring: &[(i64, i64)]) {
+        self.command(Self::MOVE_TO, 1);
+        self.point(ring[0]);
+        self.command(Self::LINE_TO, ring.len() - 1);
+        for &point in &ring[1..] {
+            self.point(point);
+        }
+        self.command(Self::CLOSE_PATH, 1);
+    }
+}
+
+impl FogMap {
+    /// The visited areas of the tile `x`, `y` at zoom `z` as a Mapbox Vector Tile with
+    /// coordinates from 0 to `extent`, addressed like [`crate::TileRendererTrait::render_image`].
+    /// A tile without visited areas is empty, which is a valid tile, and so is a tile of a zoom
+    /// outside `0..=`[`PIXEL_ZOOM`].
+    pub fn render_mvt(&self, z: i16, x: i64, y: i64, extent: u32) -> Vec<u8> {
+        if !(0..=PIXEL_ZOOM).contains(&z) {
+            return Vec::new();
+        }
+        let extent = extent.max(1);
+        // the cells are at least a unit of the tile
+        let cell_zoom = (z + (31 - extent.leading_zeros()) as i16).min(PIXEL_ZOOM);
+        let cells_per_tile = 1i64 << (cell_zoom - z);
+        let scale = extent as f64 / cells_per_tile as f64;
+        let buffer = (extent / BUFFER_FRACTION) as f64 / scale;
+        let buffer = buffer.ceil() as i64;
+        let (x0, y0) = (x * cells_per_tile, y * cells_per_tile);
+        let xs = x0 - buffer..=x0 + cells_per_tile - 1 + buffer;
+        let ys = y0 - buffer..=y0 + cells_per_tile - 1 + buffer;
+
+        let cells = visited_cells(self, cell_zoom, &xs, &ys);
+        if cells.is_empty() {
+            return Vec::new();
+        }
+        // corners rounded onto the same point are dropped, so that no line has a zero length
+        let to_tile = |ring: &[(i64, i64)]| -> Vec<(i64, i64)> {
+            let mut points: Vec<(i64, i64)> = ring
+                .iter()
+                .map(|&(cell_x, cell_y)| {
+                    (
+                        ((cell_x - x0) as f64 * scale).round() as i64,
+                        ((cell_y - y0) as f64 * scale).round() as i64,
+                    )
+                })
+                .collect();
+            points.dedup();
+            while points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            points
+        };
+        // exteriors are clockwise with y going down, as the specification requires
+        let mut geometry = Geometry::default();
+        for (exterior, holes) in group_rings(trace_rings(&cells)) {
+            let exterior = to_tile(&exterior);
+            if exterior.len() < 3 {
+                continue;
+            }
+            geometry.ring(&exterior);
+            for hole in holes {
+                let hole = to_tile(&hole);
+                if hole.len() >= 3 {
+                    geometry.ring(&hole);
+                }
+            }
+        }
+
+        const POLYGON: u64 = 3;
+        let mut feature = Message::default();
+        feature.uint(3, POLYGON);
+        feature.packed(4, &geometry.commands);
+
+        let mut layer = Message::default();
+        layer.uint(15, 2);
+        layer.bytes(1, LAYER_NAME.as_bytes());
+        layer.bytes(2, &feature.0);
+        layer.uint(5, extent as u64);
+
+        let mut tile = Message::default();
+        tile.bytes(3, &layer.0);
+        tile.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields of a message, as (field, varint or bytes).
+    fn fields(mut data: &[u8]) -> Vec<(u64, Result<u64, &[u8]>)> {
+        let varint = |data: &mut &[u8]| {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = data[0];
+                *data = &data[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        };
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            if key & 7 == 0 {
+                fields.push((key >> 3, Ok(varint(&mut data))));
+            } else {
+                let len = varint(&mut data) as usize;
+                fields.push((key >> 3, Err(&data[..len])));
+                data = &data[len..];
+            }
+        }
+        fields
+    }
+
+    // The rings of a polygon geometry, in tile coordinates.
+    fn rings(geometry: &[u8]) -> Vec<Vec<(i64, i64)>> {
+        let mut values = Vec::new();
+        let mut data = geometry;
+        while !data.is_empty() {
+            let mut value = 0u64;
+            for shift in (0..).step_by(7) {
+                let byte = data[0];
+                data = &data[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            values.push(value as u32);
+        }
+        let unzigzag = |value: u32| ((value >> 1) as i64) ^ -((value & 1) as i64);
+        let mut rings = Vec::new();
+        let mut cursor = (0, 0);
+        let mut i = 0;
+        while i < values.len() {
+            let (id, count) = (values[i] & 7, (values[i] >> 3) as usize);
+            i += 1;
+            if id == 1 {
+                rings.push(Vec::new());
+            }
+            if id == 7 {
+                continue;
+            }
+            for _ in 0..count {
+                cursor = (
+                    cursor.0 + unzigzag(values[i]),
+                    cursor.1 + unzigzag(values[i + 1]),
+                );
+                rings.last_mut().unwrap().push(cursor);
+                i += 2;
+            }
+        }
+        rings
+    }
+
+    #[test]
+    fn test_render_mvt() {
+        let mut fogmap = FogMap::new();
+        // a square loop, with a hole in it
+        fogmap.add_line(121.41, 31.21, 121.43, 31.21);
+        fogmap.add_line(121.43, 31.21, 121.43, 31.23);
+        fogmap.add_line(121.43, 31.23, 121.41, 31.23);
+        fogmap.add_line(121.41, 31.23, 121.41, 31.21);
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.42, 31.22, 12);
+        assert!(fogmap.render_mvt(12, x + 2, y, 4096).is_empty());
+
+        let tile = fogmap.render_mvt(12, x, y, 4096);
+        let layers = fields(&tile);
+        assert_eq!(layers.len(), 1);
+        let layer = fields(layers[0].1.unwrap_err());
+        assert!(layer.contains(&(15, Ok(2))));
+        assert!(layer.contains(&(1, Err(b"explored".as_slice()))));
+        assert!(layer.contains(&(5, Ok(4096))));
+        let feature = layer.iter().find(|field| field.0 == 2).unwrap();
+        let feature = fields(feature.1.unwrap_err());
+        assert!(feature.contains(&(3, Ok(3))));
+        let geometry = feature.iter().find(|field| field.0 == 4).unwrap();
+
+        let rings = rings(geometry.1.unwrap_err());
+        assert!(rings.len() >= 2);
+        for point in rings.iter().flatten() {
+            assert!((-64..=4096 + 64).contains(&point.0));
+            assert!((-64..=4096 + 64).contains(&point.1));
+        }
+        // the exterior is clockwise and the hole counterclockwise, with y going down
+        let area = |ring: &Vec<(i64, i64)>| -> i64 {
+            (0..ring.len())
+                .map(|i| {
+                    let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+                    a.0 * b.1 - b.0 * a.1
+                })
+                .sum()
+        };
+        assert!(area(&rings[0]) > 0);
+        assert!(rings[1..].iter().any(|ring| area(ring) < 0));
+    }
+
+    #[test]
+    fn test_render_mvt_zoom_and_extent() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.41, 31.21, 121.43, 31.23);
+
+        // zooms outside the pixels of the map give empty tiles
+        assert!(fogmap.render_mvt(-1, 0, 0, 4096).is_empty());
+        assert!(fogmap.render_mvt(PIXEL_ZOOM + 1, 0, 0, 4096).is_empty());
+
+        // with an extent that is not a power of two, no line has a zero length
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.42, 31.22, 20);
+        for extent in [3, 100, 4000] {
+            let tile = fogmap.render_mvt(20, x, y, extent);
+            let layer = fields(fields(&tile)[0].1.unwrap_err());
+            let feature = layer.iter().find(|field| field.0 == 2).unwrap();
+            let feature = fields(feature.1.unwrap_err());
+            let geometry = feature.iter().find(|field| field.0 == 4).unwrap();
+            for ring in rings(geometry.1.unwrap_err()) {
+                assert!(ring.len() >= 3);
+                for i in 0..ring.len() {
+                    assert_ne!(ring[i], ring[(i + 1) % ring.len()]);
+                }
+            }
+        }
+    }
+}
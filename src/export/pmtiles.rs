@@ -0,0 +1,437 @@
+//! Export of rendered tiles to a PMTiles v3 archive, a single file for static hosting.
+//!
+//! Every tile of the area is written, so that clients show the fog outside the visited areas
+//! too. Identical tiles, mostly the ones covered by fog, are stored once and shared by their
+//! directory entries. The tile data is gathered in memory before the archive is written, as it
+//! comes after the directories.
+
+use super::{zoom_tiles, TileRange};
+use crate::renderer::TileRendererTrait;
+use crate::utils::{image_to_png_data, DEFAULT_BG_COLOR2, DEFAULT_FG_COLOR2};
+use crate::FogMap;
+use flate2::write::GzEncoder;
+use image::Rgba;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+
+#[derive(Debug, Clone)]
+pub struct PmtilesOptions {
+    pub range: TileRange,
+    pub bg_color: Rgba<u8>,
+    pub fg_color: Rgba<u8>,
+    /// The `name` of the tileset in the metadata.
+    pub name: String,
+}
+
+impl Default for PmtilesOptions {
+    fn default() -> Self {
+        Self {
+            range: TileRange::default(),
+            bg_color: DEFAULT_BG_COLOR2,
+            fg_color: DEFAULT_FG_COLOR2,
+            name: "fog".to_string(),
+        }
+    }
+}
+
+/// What an export has written, as counted in the header of the archive.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PmtilesSummary {
+    /// Number of tiles in the archive.
+    pub addressed_tiles: u64,
+    /// Number of directory entries, each covering a run of tiles with the same data.
+    pub tile_entries: u64,
+    /// Number of distinct tile data.
+    pub tile_contents: u64,
+}
+
+const HEADER_LENGTH: usize = 127;
+// The header and the root directory are within the first this many bytes, for clients to fetch
+// them at once.
+const ROOT_LENGTH: usize = 16384;
+
+// compression and tile type values of the header
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+const TILE_TYPE_PNG: u8 = 2;
+
+/// The id of a tile: its position along the Hilbert curve of its zoom level, after the tiles
+/// of the lower zoom levels.
+pub fn tile_id(z: i16, x: i64, y: i64) -> u64 {
+    let n = 1i64 << z;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0u64;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as i64;
+        let ry = (y & s > 0) as i64;
+        d += (s * s * ((3 * rx) ^ ry)) as u64;
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    ((1u64 << (2 * z)) - 1) / 3 + d
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    // 0 for an entry pointing to a leaf directory
+    run_length: u32,
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// A directory, with its columns one after the other and compressed.
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut out, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut out, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut out, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 when the data follows the one of the previous entry
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut out, 0);
+        } else {
+            write_varint(&mut out, entry.offset + 1);
+        }
+    }
+    gzip(&out)
+}
+
+// The root directory and the leaf directories, when the entries are too many for the root alone.
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let root = serialize_directory(entries);
+    if root.len() <= ROOT_LENGTH - HEADER_LENGTH {
+        return (root, Vec::new());
+    }
+    let mut leaf_size = 4096;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk);
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = serialize_directory(&root_entries);
+        if root.len() <= ROOT_LENGTH - HEADER_LENGTH {
+            return (root, leaves);
+        }
+        leaf_size *= 2;
+    }
+}
+
+impl FogMap {
+    /// Renders the tiles of a zoom range with `renderer` and writes them to `writer` as a
+    /// PMTiles archive.
+    pub fn export_pmtiles<W: Write>(
+        &self,
+        mut writer: W,
+        renderer: &dyn TileRendererTrait,
+        options: &PmtilesOptions,
+    ) -> Result<PmtilesSummary, String> {
+        let bounds = options.range.area(self)?;
+
+        let mut data: Vec<u8> = Vec::new();
+        // the offset and length of the data stored, by their hash
+        let mut contents: HashMap<u64, (u64, u32)> = HashMap::new();
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut fog_tile: Option<Vec<u8>> = None;
+        let mut summary = PmtilesSummary::default();
+
+        let (min_zoom, max_zoom) = (options.range.min_zoom, options.range.max_zoom);
+        for zoom in min_zoom..=max_zoom {
+            let mut tiles: Vec<(u64, i64, i64, bool)> = zoom_tiles(self, zoom, &bounds, true)
+                .into_iter()
+                .map(|(x, y, visited)| (tile_id(zoom, x, y), x, y, visited))
+                .collect();
+            tiles.sort_unstable();
+
+            for (id, x, y, visited) in tiles {
+                let render = || {
+                    let image =
+                        renderer.render_image(self, x, y, zoom, options.bg_color, options.fg_color);
+                    image_to_png_data(&image)
+                };
+                let png = if visited {
+                    render()
+                } else {
+                    fog_tile.get_or_insert_with(render).clone()
+                };
+
+                let mut hasher = DefaultHasher::new();
+                png.hash(&mut hasher);
+                let stored = contents
+                    .get(&hasher.finish())
+                    .copied()
+                    .filter(|&(offset, length)| {
+                        data[offset as usize..offset as usize + length as usize] == png[..]
+                    });
+                let (offset, length) = stored.unwrap_or_else(|| {
+                    let stored = (data.len() as u64, png.len() as u32);
+                    data.extend_from_slice(&png);
+                    contents.insert(hasher.finish(), stored);
+                    summary.tile_contents += 1;
+                    stored
+                });
+
+                summary.addressed_tiles += 1;
+                match entries.last_mut() {
+                    Some(last)
+                        if last.tile_id + last.run_length as u64 == id && last.offset == offset =>
+                    {
+                        last.run_length += 1;
+                    }
+                    _ => entries.push(Entry {
+                        tile_id: id,
+                        offset,
+                        length,
+                        run_length: 1,
+                    }),
+                }
+            }
+        }
+        summary.tile_entries = entries.len() as u64;
+
+        let (root, leaves) = build_directories(&entries);
+        let metadata = gzip(
+            serde_json::json!({
+                "name": options.name,
+                "format": "png",
+                "type": "overlay",
+            })
+            .to_string()
+            .as_bytes(),
+        );
+
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        let root_offset = HEADER_LENGTH as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + metadata.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+        for value in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data.len() as u64,
+            summary.addressed_tiles,
+            summary.tile_entries,
+            summary.tile_contents,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        // the tile data is in the order of the tile ids
+        header.push(1);
+        header.extend_from_slice(&[COMPRESSION_GZIP, COMPRESSION_NONE, TILE_TYPE_PNG]);
+        header.extend_from_slice(&[min_zoom as u8, max_zoom as u8]);
+        let e7 = |degrees: f64| ((degrees * 1e7) as i32).to_le_bytes();
+        for degrees in [bounds.west, bounds.south, bounds.east, bounds.north] {
+            header.extend_from_slice(&e7(degrees));
+        }
+        header.push(min_zoom as u8);
+        header.extend_from_slice(&e7((bounds.west + bounds.east) / 2.0));
+        header.extend_from_slice(&e7((bounds.south + bounds.north) / 2.0));
+
+        [header, root, metadata, leaves, data]
+            .iter()
+            .try_for_each(|part| writer.write_all(part))
+            .map_err(|e| format!("Failed to write the archive: {}", e))?;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Bounds;
+    use crate::renderer::TileRendererBasic;
+    use crate::utils::TileSize;
+    use flate2::read::GzDecoder;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = data[0];
+            *data = &data[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn read_directory(compressed: &[u8]) -> Vec<Entry> {
+        let mut raw = Vec::new();
+        GzDecoder::new(compressed).read_to_end(&mut raw).unwrap();
+        let mut data = raw.as_slice();
+        let n = read_varint(&mut data) as usize;
+        let mut entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 0,
+                run_length: 0
+            };
+            n
+        ];
+        let mut last_id = 0;
+        for entry in entries.iter_mut() {
+            last_id += read_varint(&mut data);
+            entry.tile_id = last_id;
+        }
+        for entry in entries.iter_mut() {
+            entry.run_length = read_varint(&mut data) as u32;
+        }
+        for entry in entries.iter_mut() {
+            entry.length = read_varint(&mut data) as u32;
+        }
+        for i in 0..n {
+            let offset = read_varint(&mut data);
+            entries[i].offset = if offset == 0 {
+                entries[i - 1].offset + entries[i - 1].length as u64
+            } else {
+                offset - 1
+            };
+        }
+        entries
+    }
+
+    #[test]
+    fn test_tile_id() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(1, 0, 0), 1);
+        assert_eq!(tile_id(1, 0, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 1, 0), 4);
+        assert_eq!(tile_id(2, 0, 0), 5);
+    }
+
+    #[test]
+    fn test_leaf_directories() {
+        // entries which do not compress well, too many for the root directory
+        let entries: Vec<Entry> = (0..100_000u64)
+            .map(|i| Entry {
+                tile_id: i * 3,
+                offset: (i * 7919) % 1_000_003,
+                length: (i % 97) as u32 + 1,
+                run_length: 1,
+            })
+            .collect();
+        let (root, leaves) = build_directories(&entries);
+        assert!(root.len() <= ROOT_LENGTH - HEADER_LENGTH);
+        assert!(!leaves.is_empty());
+        let mut read = Vec::new();
+        for pointer in read_directory(&root) {
+            assert_eq!(pointer.run_length, 0);
+            let start = pointer.offset as usize;
+            let leaf = read_directory(&leaves[start..start + pointer.length as usize]);
+            assert_eq!(leaf[0].tile_id, pointer.tile_id);
+            read.extend(leaf);
+        }
+        assert_eq!(read, entries);
+    }
+
+    #[test]
+    fn test_export_pmtiles() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+        let renderer = TileRendererBasic::new(TileSize::TileSize256);
+        let options = PmtilesOptions {
+            range: TileRange {
+                min_zoom: 10,
+                max_zoom: 14,
+                bounds: Some(Bounds {
+                    west: 121.3,
+                    south: 31.1,
+                    east: 121.5,
+                    north: 31.3,
+                }),
+            },
+            ..Default::default()
+        };
+        let mut archive = Vec::new();
+        let summary = fogmap
+            .export_pmtiles(&mut archive, &renderer, &options)
+            .unwrap();
+
+        assert_eq!(&archive[..7], b"PMTiles");
+        assert_eq!(archive[7], 3);
+        let u64_at = |i: usize| u64::from_le_bytes(archive[i..i + 8].try_into().unwrap());
+        assert_eq!(u64_at(72), summary.addressed_tiles);
+        assert_eq!((archive[100], archive[101]), (10, 14));
+        // the fog tiles share their data
+        assert!(summary.tile_contents < summary.tile_entries);
+        assert!(summary.tile_entries < summary.addressed_tiles);
+
+        let root = &archive[u64_at(8) as usize..(u64_at(8) + u64_at(16)) as usize];
+        assert_eq!(u64_at(48), 0);
+        let entries = read_directory(root);
+        assert_eq!(entries.len() as u64, summary.tile_entries);
+        let addressed: u64 = entries.iter().map(|entry| entry.run_length as u64).sum();
+        assert_eq!(addressed, summary.addressed_tiles);
+
+        let find = |z: i16, x: i64, y: i64| {
+            let id = tile_id(z, x, y);
+            let entry = entries
+                .iter()
+                .find(|entry| {
+                    (entry.tile_id..entry.tile_id + entry.run_length as u64).contains(&id)
+                })
+                .unwrap();
+            let start = (u64_at(56) + entry.offset) as usize;
+            &archive[start..start + entry.length as usize]
+        };
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.42, 31.205, 14);
+        let visited = find(14, x, y);
+        let fog = find(14, x, y + 4);
+        assert!(visited.starts_with(b"\x89PNG") && fog.starts_with(b"\x89PNG"));
+        assert_ne!(visited, fog);
+        assert_eq!(fog, find(14, x, y - 4));
+        assert_eq!(fog, find(13, x / 2, y / 2 + 2));
+    }
+}
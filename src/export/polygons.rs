@@ -0,0 +1,353 @@
+//! Vectorization of the visited areas into polygons, and their export as GeoJSON.
+//!
+//! The visited cells are traced with marching squares into rings following the cell edges, so
+//! areas touching across blocks and tiles are a single polygon and unvisited areas enclosed by
+//! visited ones are holes. Cells touching only at a corner are kept apart.
+
+use super::{cell_to_lng_lat, visited_cells, Bounds, PIXEL_ZOOM};
+use crate::FogMap;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// A polygon in degrees, as `(lng, lat)`. The rings are closed and, as in GeoJSON, the exterior
+/// is counterclockwise and the holes are clockwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+    pub exterior: Vec<(f64, f64)>,
+    pub holes: Vec<Vec<(f64, f64)>>,
+}
+
+/// A ring of cell corners, not closed. With y going down, exteriors turn clockwise and holes
+/// counterclockwise, the visited cells being on the right of the edges.
+pub(crate) type Ring = Vec<(i64, i64)>;
+
+/// Traces the boundaries of the cells into rings, without collinear corners.
+pub(crate) fn trace_rings(cells: &HashSet<(i64, i64)>) -> Vec<Ring> {
+    // the directions of the edges leaving each corner, two at the corners of a saddle
+    let mut edges: HashMap<(i64, i64), Vec<(i64, i64)>> = HashMap::new();
+    for &(x, y) in cells {
+        let sides = [
+            ((x, y - 1), (x, y), (1, 0)),
+            ((x + 1, y), (x + 1, y), (0, 1)),
+            ((x, y + 1), (x + 1, y + 1), (-1, 0)),
+            ((x - 1, y), (x, y + 1), (0, -1)),
+        ];
+        for (neighbour, start, direction) in sides {
+            if !cells.contains(&neighbour) {
+                edges.entry(start).or_default().push(direction);
+            }
+        }
+    }
+
+    let mut starts: Vec<(i64, i64)> = edges.keys().copied().collect();
+    starts.sort_unstable();
+    let mut rings = Vec::new();
+    for start in starts {
+        while let Some(first) = edges
+            .get_mut(&start)
+            .and_then(|directions| directions.pop())
+        {
+            let mut ring = vec![start];
+            let mut corner = (start.0 + first.0, start.1 + first.1);
+            let mut direction = first;
+            while corner != start {
+                let directions = edges.get_mut(&corner).expect("the boundary is closed");
+                // turning right first keeps the cells touching at a corner apart
+                let (dx, dy) = direction;
+                let next = [(-dy, dx), (dx, dy), (dy, -dx)]
+                    .iter()
+                    .find_map(|turn| {
+                        let index = directions.iter().position(|d| d == turn)?;
+                        Some(directions.swap_remove(index))
+                    })
+                    .expect("the boundary is closed");
+                if next != direction {
+                    ring.push(corner);
+                }
+                direction = next;
+                corner = (corner.0 + next.0, corner.1 + next.1);
+            }
+            // the start is collinear when the ring does not turn there
+            if direction == first {
+                ring.remove(0);
+            }
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+// Twice the signed area, positive for exteriors.
+fn area(ring: &[(i64, i64)]) -> i64 {
+    (0..ring.len())
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            a.0 * b.1 - b.0 * a.1
+        })
+        .sum()
+}
+
+fn contains(ring: &[(i64, i64)], (x, y): (f64, f64)) -> bool {
+    let mut inside = false;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+        let (ax, ay, bx, by) = (a.0 as f64, a.1 as f64, b.0 as f64, b.1 as f64);
+        if (ay > y) != (by > y) && x < ax + (y - ay) / (by - ay) * (bx - ax) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Groups the rings into exteriors with the holes directly within them.
+pub(crate) fn group_rings(rings: Vec<Ring>) -> Vec<(Ring, Vec<Ring>)> {
+    let (exteriors, holes): (Vec<Ring>, Vec<Ring>) =
+        rings.into_iter().partition(|ring| area(ring) > 0);
+    let bounds: Vec<_> = exteriors
+        .iter()
+        .map(|ring| {
+            let xs = ring.iter().map(|corner| corner.0);
+            let ys = ring.iter().map(|corner| corner.1);
+            (xs.clone().min(), xs.max(), ys.clone().min(), ys.max())
+        })
+        .collect();
+    let mut polygons: Vec<(Ring, Vec<Ring>)> = exteriors
+        .into_iter()
+        .map(|exterior| (exterior, Vec::new()))
+        .collect();
+    for hole in holes {
+        // the centre of the unvisited cell on the left of the first edge
+        let (a, b) = (hole[0], hole[1 % hole.len()]);
+        let (dx, dy) = ((b.0 - a.0).signum(), (b.1 - a.1).signum());
+        let point = (
+            a.0 as f64 + (dx + dy) as f64 * 0.5,
+            a.1 as f64 + (dy - dx) as f64 * 0.5,
+        );
+        let parent = (0..polygons.len())
+            .filter(|&i| {
+                let (min_x, max_x, min_y, max_y) = bounds[i];
+                (min_x.unwrap() as f64..=max_x.unwrap() as f64).contains(&point.0)
+                    && (min_y.unwrap() as f64..=max_y.unwrap() as f64).contains(&point.1)
+                    && contains(&polygons[i].0, point)
+            })
+            .min_by_key(|&i| area(&polygons[i].0));
+        if let Some(parent) = parent {
+            polygons[parent].1.push(hole);
+        }
+    }
+    polygons
+}
+
+// Douglas-Peucker on a closed ring, in cells.
+fn simplify_ring(ring: &[(f64, f64)], tolerance: f64) -> Vec<(f64, f64)> {
+    if ring.len() < 4 {
+        return ring.to_vec();
+    }
+    // the ring is split at its first corner and the corner farthest from it
+    let distance = |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).hypot(a.1 - b.1);
+    let far = (1..ring.len())
+        .max_by(|&i, &j| distance(ring[0], ring[i]).total_cmp(&distance(ring[0], ring[j])))
+        .unwrap();
+    let mut keep = vec![false; ring.len() + 1];
+    keep[0] = true;
+    keep[far] = true;
+    let point = |i: usize| ring[i % ring.len()];
+    let mut ranges = vec![(0, far), (far, ring.len())];
+    while let Some((first, last)) = ranges.pop() {
+        let (a, b) = (point(first), point(last));
+        let length = distance(a, b);
+        let (index, max_distance) = (first + 1..last)
+            .map(|i| {
+                let p = point(i);
+                let distance = if length == 0.0 {
+                    distance(a, p)
+                } else {
+                    ((b.1 - a.1) * (p.0 - a.0) - (b.0 - a.0) * (p.1 - a.1)).abs() / length
+                };
+                (i, distance)
+            })
+            .fold(
+                (first, 0.0),
+                |max, item| if item.1 > max.1 { item } else { max },
+            );
+        if max_distance > tolerance {
+            keep[index] = true;
+            ranges.push((first, index));
+            ranges.push((index, last));
+        }
+    }
+    (0..ring.len())
+        .filter(|&i| keep[i])
+        .map(|i| ring[i])
+        .collect()
+}
+
+// Converts a ring of cell corners to a closed ring in degrees, or `None` when simplifying
+// collapsed it.
+fn to_degrees(ring: &[(i64, i64)], zoom: i16, tolerance: Option<f64>) -> Option<Vec<(f64, f64)>> {
+    let ring: Vec<(f64, f64)> = ring.iter().map(|&(x, y)| (x as f64, y as f64)).collect();
+    let mut ring = match tolerance {
+        Some(tolerance) => simplify_ring(&ring, tolerance),
+        None => ring,
+    };
+    if ring.len() < 3 {
+        return None;
+    }
+    ring.push(ring[0]);
+    // the exterior turns clockwise when looking at the map, GeoJSON wants it counterclockwise
+    Some(
+        ring.into_iter()
+            .rev()
+            .map(|(x, y)| cell_to_lng_lat(x, y, zoom))
+            .collect(),
+    )
+}
+
+/// A GeoJSON `MultiPolygon` of the polygons.
+pub fn to_multi_polygon(polygons: &[Polygon]) -> Value {
+    let ring = |ring: &Vec<(f64, f64)>| -> Vec<[f64; 2]> {
+        ring.iter().map(|&(lng, lat)| [lng, lat]).collect()
+    };
+    let coordinates: Vec<Vec<Vec<[f64; 2]>>> = polygons
+        .iter()
+        .map(|polygon| {
+            std::iter::once(&polygon.exterior)
+                .chain(&polygon.holes)
+                .map(ring)
+                .collect()
+        })
+        .collect();
+    json!({
+        "type": "MultiPolygon",
+        "coordinates": coordinates,
+    })
+}
+
+impl FogMap {
+    /// The visited areas within `bbox` as polygons, traced on the cells of `zoom`. The zoom is
+    /// at most [`PIXEL_ZOOM`], the resolution of the map.
+    pub fn to_polygons(&self, bbox: &Bounds, zoom: i16) -> Vec<Polygon> {
+        self.to_simplified_polygons(bbox, zoom, None)
+    }
+
+    /// The same as [`FogMap::to_polygons`], simplifying the rings with the Douglas-Peucker
+    /// algorithm when `tolerance`, in cells of `zoom`, is set. A simplified ring may cross
+    /// another one, and rings smaller than the tolerance are removed.
+    pub fn to_simplified_polygons(
+        &self,
+        bbox: &Bounds,
+        zoom: i16,
+        tolerance: Option<f64>,
+    ) -> Vec<Polygon> {
+        let zoom = zoom.clamp(0, PIXEL_ZOOM);
+        let (xs, ys) = bbox.cell_ranges(zoom);
+        let cells = visited_cells(self, zoom, &xs, &ys);
+        let mut polygons = Vec::new();
+        for (exterior, holes) in group_rings(trace_rings(&cells)) {
+            let Some(exterior) = to_degrees(&exterior, zoom, tolerance) else {
+                continue;
+            };
+            let holes = holes
+                .iter()
+                .filter_map(|hole| to_degrees(hole, zoom, tolerance))
+                .collect();
+            polygons.push(Polygon { exterior, holes });
+        }
+        polygons
+    }
+
+    /// The visited areas within `bbox` as a GeoJSON `MultiPolygon`, see
+    /// [`FogMap::to_simplified_polygons`].
+    pub fn to_geojson(&self, bbox: &Bounds, zoom: i16, tolerance: Option<f64>) -> Value {
+        to_multi_polygon(&self.to_simplified_polygons(bbox, zoom, tolerance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(rows: &[&str]) -> HashSet<(i64, i64)> {
+        let mut cells = HashSet::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c == '#' {
+                    cells.insert((x as i64, y as i64));
+                }
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn test_trace_rings() {
+        let polygons = group_rings(trace_rings(&cells(&[
+            "#####.", "#...#.", "#.#.#.", "#...##", "#####.", ".....#",
+        ])));
+        assert_eq!(polygons.len(), 3);
+        let (exterior, holes) = polygons
+            .iter()
+            .find(|(exterior, _)| exterior.contains(&(0, 0)))
+            .unwrap();
+        // the notch on the right side
+        assert_eq!(exterior.len(), 8);
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes[0].len(), 4);
+        assert_eq!(area(&holes[0]), -2 * 9);
+        // the island in the hole and the cell touching only at a corner
+        assert!(polygons
+            .iter()
+            .any(|(exterior, holes)| exterior.contains(&(2, 2)) && holes.is_empty()));
+        assert!(polygons
+            .iter()
+            .any(|(exterior, _)| exterior == &vec![(5, 5), (6, 5), (6, 6), (5, 6)]));
+    }
+
+    #[test]
+    fn test_to_polygons() {
+        let mut fogmap = FogMap::new();
+        // across the boundary of two tiles of the map, a tile being about 0.7 degrees wide
+        fogmap.add_line(120.90, 31.2, 121.0, 31.2);
+        fogmap.add_line(120.90, 31.25, 121.0, 31.25);
+        fogmap.add_line(120.90, 31.2, 120.90, 31.25);
+        fogmap.add_line(121.0, 31.2, 121.0, 31.25);
+        let bbox = Bounds {
+            west: 120.0,
+            south: 31.0,
+            east: 122.0,
+            north: 32.0,
+        };
+
+        let polygons = fogmap.to_polygons(&bbox, 16);
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].holes.len(), 1);
+        let exterior = &polygons[0].exterior;
+        assert_eq!(exterior.first(), exterior.last());
+        // counterclockwise, with y going up
+        let area: f64 = exterior
+            .windows(2)
+            .map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1)
+            .sum();
+        assert!(area > 0.0);
+        for &(lng, lat) in exterior {
+            assert!((120.89..121.01).contains(&lng) && (31.19..31.26).contains(&lat));
+        }
+
+        // simplifying leaves the rectangles
+        let polygons = fogmap.to_simplified_polygons(&bbox, 16, Some(2.0));
+        assert_eq!(polygons[0].exterior.len(), 5);
+        assert_eq!(polygons[0].holes[0].len(), 5);
+        let geojson = fogmap.to_geojson(&bbox, 16, Some(2.0));
+        assert_eq!(geojson["type"], "MultiPolygon");
+        assert_eq!(geojson["coordinates"][0][1].as_array().unwrap().len(), 5);
+
+        // outside of the bounds
+        let bbox = Bounds {
+            west: 0.0,
+            south: 0.0,
+            east: 1.0,
+            north: 1.0,
+        };
+        assert!(fogmap.to_polygons(&bbox, 16).is_empty());
+    }
+}
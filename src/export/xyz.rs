@@ -0,0 +1,226 @@
+//! Export of rendered tiles to a `{z}/{x}/{y}.png` directory tree, for static file servers.
+//!
+//! The tiles of a zoom level are rendered across the threads of a pool, so the renderer must be
+//! shareable between threads.
+
+use super::{zoom_tiles, TileRange};
+use crate::renderer::TileRendererTrait;
+use crate::utils::{image_to_png_data, DEFAULT_BG_COLOR2, DEFAULT_FG_COLOR2};
+use crate::FogMap;
+use image::Rgba;
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to write for the tiles of the area without visited areas.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum EmptyTilePolicy {
+    /// Writes nothing, leaving the fog to the client.
+    #[default]
+    Skip,
+    /// Links the tile to a single rendering of the fog, `empty.png` at the root of the tree.
+    Placeholder,
+    /// Writes an empty `{y}.empty` file instead of the tile.
+    Marker,
+}
+
+#[derive(Debug, Clone)]
+pub struct XyzOptions {
+    pub range: TileRange,
+    pub bg_color: Rgba<u8>,
+    pub fg_color: Rgba<u8>,
+    pub empty_tiles: EmptyTilePolicy,
+    /// Names the tiles `{y}@2x.png`, for a renderer of twice the usual tile size of 256 pixels.
+    /// The export fails with a renderer of another size.
+    pub retina: bool,
+}
+
+impl Default for XyzOptions {
+    fn default() -> Self {
+        Self {
+            range: TileRange::default(),
+            bg_color: DEFAULT_BG_COLOR2,
+            fg_color: DEFAULT_FG_COLOR2,
+            empty_tiles: EmptyTilePolicy::Skip,
+            retina: false,
+        }
+    }
+}
+
+/// What an export has written.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct XyzSummary {
+    /// Number of tiles with visited areas.
+    pub rendered: usize,
+    /// Number of placeholders or markers written for the other tiles.
+    pub empty: usize,
+}
+
+impl FogMap {
+    /// Renders the tiles of a zoom range with `renderer` into the directory `root`.
+    pub fn export_xyz(
+        &self,
+        root: &Path,
+        renderer: &(dyn TileRendererTrait + Sync),
+        options: &XyzOptions,
+    ) -> Result<XyzSummary, String> {
+        let bounds = options.range.area(self)?;
+        let tile_size = renderer.get_tile_size().size();
+        if options.retina && tile_size != 512 {
+            return Err(format!(
+                "Retina tiles need a renderer of 512 pixels, not {}",
+                tile_size
+            ));
+        }
+        let mut summary = XyzSummary::default();
+        let io_error =
+            |path: &Path, e: std::io::Error| format!("Failed to write {}: {}", path.display(), e);
+        fs::create_dir_all(root).map_err(|e| io_error(root, e))?;
+        let suffix = if options.retina { "@2x" } else { "" };
+
+        let placeholder = root.join(format!("empty{}.png", suffix));
+        if options.empty_tiles == EmptyTilePolicy::Placeholder {
+            // the fog is the same on every tile without visited areas
+            let image =
+                renderer.render_image(&FogMap::new(), 0, 0, 0, options.bg_color, options.fg_color);
+            // the tiles linked to the placeholder of a previous export are left as they are
+            let _ = fs::remove_file(&placeholder);
+            fs::write(&placeholder, image_to_png_data(&image))
+                .map_err(|e| io_error(&placeholder, e))?;
+        }
+
+        for zoom in options.range.min_zoom..=options.range.max_zoom {
+            let all = options.empty_tiles != EmptyTilePolicy::Skip;
+            let tiles = zoom_tiles(self, zoom, &bounds, all);
+
+            // whether each tile has visited areas
+            let written = tiles
+                .par_iter()
+                .map(|&(x, y, visited)| -> Result<bool, String> {
+                    let dir = root.join(zoom.to_string()).join(x.to_string());
+                    fs::create_dir_all(&dir).map_err(|e| io_error(&dir, e))?;
+                    let path: PathBuf;
+                    let result = if visited {
+                        path = dir.join(format!("{}{}.png", y, suffix));
+                        // the tile may be a link to the placeholder, which must not be written
+                        let _ = fs::remove_file(&path);
+                        let image = renderer.render_image(
+                            self,
+                            x,
+                            y,
+                            zoom,
+                            options.bg_color,
+                            options.fg_color,
+                        );
+                        fs::write(&path, image_to_png_data(&image))
+                    } else if options.empty_tiles == EmptyTilePolicy::Placeholder {
+                        path = dir.join(format!("{}{}.png", y, suffix));
+                        let _ = fs::remove_file(&path);
+                        // copies the placeholder where links are not supported
+                        fs::hard_link(&placeholder, &path)
+                            .or_else(|_| fs::copy(&placeholder, &path).map(|_| ()))
+                    } else {
+                        path = dir.join(format!("{}{}.empty", y, suffix));
+                        fs::write(&path, [])
+                    };
+                    result.map_err(|e| io_error(&path, e))?;
+                    Ok(visited)
+                })
+                .collect::<Result<Vec<bool>, String>>()?;
+            let rendered = written.iter().filter(|&&visited| visited).count();
+            summary.rendered += rendered;
+            summary.empty += written.len() - rendered;
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Bounds;
+    use crate::renderer::TileRendererBasic;
+    use crate::utils::TileSize;
+
+    #[test]
+    fn test_export_xyz() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(121.40, 31.20, 121.44, 31.21);
+        let root = std::env::temp_dir().join(format!("fogcore-xyz-{}", std::process::id()));
+        let renderer = TileRendererBasic::new(TileSize::TileSize256);
+        let bounds = Bounds {
+            west: 121.3,
+            south: 31.1,
+            east: 121.5,
+            north: 31.3,
+        };
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.42, 31.205, 14);
+        let tile = |name: &str| root.join("14").join(x.to_string()).join(name);
+
+        let mut options = XyzOptions {
+            range: TileRange {
+                min_zoom: 12,
+                max_zoom: 14,
+                bounds: Some(bounds),
+            },
+            ..Default::default()
+        };
+        let summary = fogmap.export_xyz(&root, &renderer, &options).unwrap();
+        assert_eq!(summary.empty, 0);
+        assert!(fs::read(tile(&format!("{}.png", y)))
+            .unwrap()
+            .starts_with(b"\x89PNG"));
+        assert!(!tile(&format!("{}.png", y + 4)).exists());
+
+        options.empty_tiles = EmptyTilePolicy::Marker;
+        let marked = fogmap.export_xyz(&root, &renderer, &options).unwrap();
+        assert_eq!(marked.rendered, summary.rendered);
+        assert!(marked.empty > 0);
+        assert_eq!(
+            fs::read(tile(&format!("{}.empty", y + 4))).unwrap().len(),
+            0
+        );
+
+        options.empty_tiles = EmptyTilePolicy::Placeholder;
+        options.retina = true;
+        assert!(fogmap.export_xyz(&root, &renderer, &options).is_err());
+        let renderer = TileRendererBasic::new(TileSize::TileSize512);
+        let placed = fogmap.export_xyz(&root, &renderer, &options).unwrap();
+        assert_eq!(placed, marked);
+        let placeholder = fs::read(root.join("empty@2x.png")).unwrap();
+        assert_eq!(
+            fs::read(tile(&format!("{}@2x.png", y + 4))).unwrap(),
+            placeholder
+        );
+        let retina = fs::read(tile(&format!("{}@2x.png", y))).unwrap();
+        assert_ne!(retina, placeholder);
+        assert_eq!(image::load_from_memory(&retina).unwrap().width(), 512);
+
+        // a placeholder tile which gets visited areas is rendered without touching the others
+        let (lng, lat) = crate::utils::tile_x_y_to_lng_lat(x as i32, y as i32 + 4, 14);
+        fogmap.add_line(lng + 0.001, lat - 0.001, lng + 0.01, lat - 0.01);
+        options.range.min_zoom = 14;
+        fogmap.export_xyz(&root, &renderer, &options).unwrap();
+        assert_eq!(fs::read(root.join("empty@2x.png")).unwrap(), placeholder);
+        // including those of the zoom levels not exported again
+        let outside = root
+            .join("13")
+            .join((x / 2).to_string())
+            .join(format!("{}@2x.png", y / 2 - 2));
+        assert_eq!(fs::read(outside).unwrap(), placeholder);
+        assert_eq!(
+            fs::read(tile(&format!("{}@2x.png", y - 4))).unwrap(),
+            placeholder
+        );
+        assert_ne!(
+            fs::read(tile(&format!("{}@2x.png", y + 4))).unwrap(),
+            placeholder
+        );
+        fs::remove_dir_all(&root).unwrap();
+
+        options.range.bounds = None;
+        assert!(FogMap::new()
+            .export_xyz(&root, &renderer, &options)
+            .is_err());
+    }
+}
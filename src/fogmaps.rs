@@ -1,13 +1,14 @@
+use crate::datum::{out_of_china, Datum};
 use miniz_oxide::inflate::decompress_to_vec_zlib;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::PI;
-use std::io::Read;
+use std::io::{Read, Seek};
 
 const FILENAME_MASK1: &str = "olhwjsktri";
 #[allow(dead_code)]
 const FILENAME_MASK2: &str = "eizxdwknmo";
 
-const MAP_WIDTH_OFFSET: i16 = 9;
+pub(crate) const MAP_WIDTH_OFFSET: i16 = 9;
 const MAP_WIDTH: i64 = 1 << MAP_WIDTH_OFFSET;
 pub const TILE_WIDTH_OFFSET: i16 = 7;
 const TILE_WIDTH: i64 = 1 << TILE_WIDTH_OFFSET;
@@ -18,7 +19,90 @@ const BLOCK_EXTRA_DATA: usize = 3;
 const BLOCK_SIZE: usize = BLOCK_BITMAP_SIZE + BLOCK_EXTRA_DATA;
 pub const BITMAP_WIDTH_OFFSET: i16 = 6;
 pub const BITMAP_WIDTH: i64 = 1 << BITMAP_WIDTH_OFFSET;
-const ALL_OFFSET: i16 = TILE_WIDTH_OFFSET + BITMAP_WIDTH_OFFSET;
+pub(crate) const ALL_OFFSET: i16 = TILE_WIDTH_OFFSET + BITMAP_WIDTH_OFFSET;
+// the width of the whole map in pixels
+pub(crate) const PIXEL_MAP_WIDTH: i64 = 1 << (ALL_OFFSET + MAP_WIDTH_OFFSET);
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+// the latitude of the edges of the map in Web Mercator
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// How a line segment is turned into pixels by [`FogMap::add_line_with_mode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LineMode {
+    /// The classic Bresenham line, 8-connected: consecutive pixels may only touch by a corner.
+    #[default]
+    Bresenham,
+    /// A 4-connected "supercover" line: every diagonal step is split into two axis steps, so
+    /// consecutive pixels always share an edge and diagonal tracks have no gaps.
+    Supercover,
+}
+
+impl LineMode {
+    // the initial error term for a line with the given absolute deltas along its major and
+    // minor axis.
+    fn initial_error(self, major: i64, minor: i64) -> i64 {
+        match self {
+            LineMode::Bresenham => 2 * minor - major,
+            LineMode::Supercover => minor - major,
+        }
+    }
+
+    // whether the rasterization should go on, `p` being the error term carried so far.
+    // A supercover line may still need minor-axis steps once its major axis has reached the end.
+    fn continues(self, pos: i64, end: i64, p: i64) -> bool {
+        pos < end || (self == LineMode::Supercover && p > 0)
+    }
+}
+
+/// What a mutation of a [`FogMap`] changed, computed while the data is being written.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExplorationDelta {
+    /// Number of pixels that were not visited before.
+    pub new_pixels: u64,
+    /// Tiles that did not exist before, as `(tile_x, tile_y)`.
+    pub new_tiles: HashSet<(i64, i64)>,
+    /// Blocks that did not exist before, as `(tile_x, tile_y, block_x, block_y)`.
+    pub new_blocks: HashSet<(i64, i64, i64, i64)>,
+    /// Tiles that were written to, whether or not anything new was visited in them.
+    pub touched_tiles: HashSet<(i64, i64)>,
+}
+
+impl ExplorationDelta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether nothing new was visited.
+    pub fn is_empty(&self) -> bool {
+        self.new_pixels == 0
+    }
+
+    /// Adds the changes of another mutation to this one.
+    pub fn merge(&mut self, other: ExplorationDelta) {
+        self.new_pixels += other.new_pixels;
+        self.new_tiles.extend(other.new_tiles);
+        self.new_blocks.extend(other.new_blocks);
+        self.touched_tiles.extend(other.touched_tiles);
+    }
+
+    fn add_tile_delta(&mut self, (tile_x, tile_y): (i64, i64), tile_delta: TileDelta) {
+        self.new_pixels += tile_delta.new_pixels;
+        self.new_blocks.extend(
+            tile_delta
+                .new_blocks
+                .into_iter()
+                .map(|(block_x, block_y)| (tile_x, tile_y, block_x, block_y)),
+        );
+        self.touched_tiles.insert((tile_x, tile_y));
+    }
+}
+
+// What a mutation changed within a single tile.
+#[derive(Default)]
+struct TileDelta {
+    new_pixels: u64,
+    new_blocks: Vec<(i64, i64)>,
+}
 
 /// An in-memory efficient representation of a persons tracks on the Earth.
 #[derive(Default)]
@@ -34,37 +118,50 @@ impl FogMap {
         }
     }
 
+    /// Decodes the tile `(x, y)` held by a data file of the `Fog of World` App from its name.
+    pub fn parse_fow_file_name(file_name: &str) -> Option<(i64, i64)> {
+        let id = file_name.get(4..file_name.len().checked_sub(2)?)?;
+        if id.is_empty() || id.len() > 6 {
+            return None;
+        }
+        let id = id.chars().try_fold(0, |id, id_masked| {
+            let digit = FILENAME_MASK1.find(id_masked)?;
+            Some(id * 10 + digit as i64)
+        })?;
+        (id < MAP_WIDTH * MAP_WIDTH).then_some((id % MAP_WIDTH, id / MAP_WIDTH))
+    }
+
     /// Adds tracks by importing from a data file of the `Fog of World` App.
     ///
     /// Note that this operations will NOT REPLACE the existing tracks in FogMap, this operation is purely incremental.
-    pub fn add_fow_file(&mut self, file_name: &str, data: Vec<u8>) {
-        // TODO: current implementation will replace the tile if exist, change it to additive editing.
-
-        let mut filename_encoding = std::collections::HashMap::new();
-        for (i, char) in FILENAME_MASK1.chars().enumerate() {
-            filename_encoding.insert(char, i);
-        }
-        // TODO: apply some checks here
-        let id = file_name[4..file_name.len() - 2]
-            .chars()
-            .map(|id_masked| filename_encoding[&id_masked].to_string())
-            .collect::<String>()
-            .parse::<i64>()
-            .unwrap();
-
-        let x = id % MAP_WIDTH;
-        let y = id / MAP_WIDTH;
-
-        // println!("parsing tile x:{} y:{}", x, y);
-
-        let tile = self.tiles.entry((x, y)).or_default();
-
-        let data_inflate = decompress_to_vec_zlib(&data).unwrap();
-
-        // println!("inflated data len: {}", data_inflate.len());
+    ///
+    /// Panics if the file is invalid, see [`FogMap::add_fow_reader`] for a fallible version.
+    pub fn add_fow_file(&mut self, file_name: &str, data: Vec<u8>) -> ExplorationDelta {
+        self.add_fow_reader(file_name, &data[..]).unwrap()
+    }
 
-        let header = &data_inflate[0..TILE_HEADER_SIZE];
+    /// Adds tracks by importing from a data file of the `Fog of World` App, read from `reader`.
+    ///
+    /// The file is checked as a whole before anything is added, an invalid file leaves the
+    /// FogMap unchanged.
+    pub fn add_fow_reader<R: Read>(
+        &mut self,
+        file_name: &str,
+        mut reader: R,
+    ) -> Result<ExplorationDelta, String> {
+        let (x, y) = Self::parse_fow_file_name(file_name)
+            .ok_or_else(|| format!("Not a Fog of World file name: {}", file_name))?;
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+        let data_inflate = decompress_to_vec_zlib(&data)
+            .map_err(|e| format!("Failed to inflate {}: {:?}", file_name, e.status))?;
+        let header = data_inflate
+            .get(0..TILE_HEADER_SIZE)
+            .ok_or_else(|| format!("{} is truncated", file_name))?;
 
+        let mut blocks = Vec::new();
         for i in 0..TILE_HEADER_LEN {
             // parse two u8 as a single u16 according to little endian
             let index = (i as usize) * 2;
@@ -74,29 +171,45 @@ impl FogMap {
                 let block_y: i64 = i / TILE_WIDTH;
                 let start_offset = TILE_HEADER_SIZE + ((block_idx - 1) as usize) * BLOCK_SIZE;
                 let end_offset = start_offset + BLOCK_SIZE;
-                let data = data_inflate[start_offset..end_offset].to_vec();
-                let block = Block::new_with_data(data);
-                // println!("inserting block {}-{}", block_x, block_y);
-                tile.add_by_blocks(block_x, block_y, block)
+                let data = data_inflate
+                    .get(start_offset..end_offset)
+                    .ok_or_else(|| format!("{} is truncated", file_name))?
+                    .to_vec();
+                blocks.push((block_x, block_y, Block::new_with_data(data)));
             }
         }
 
-        // println!("inflated data len: {:?}", data_inflate.len());
+        let mut delta = ExplorationDelta::new();
+        let mut tile_delta = TileDelta::default();
+        let tile = self.get_or_insert_tile((x, y), &mut delta);
+        for (block_x, block_y, block) in blocks {
+            tile.merge_block(block_x, block_y, &block, &mut tile_delta)
+        }
+        delta.add_tile_delta((x, y), tile_delta);
+        Ok(delta)
     }
 
     /// Adds tracks by importing from a zip file containing multiple FOW data files.
     ///
     /// This will process all files within the zip archive and attempt to import them
     /// as FOW data files. Invalid files will be skipped.
-    pub fn add_fow_zip(&mut self, zip_data: &[u8]) -> Result<(), String> {
-        let reader = std::io::Cursor::new(zip_data);
+    pub fn add_fow_zip(&mut self, zip_data: &[u8]) -> Result<ExplorationDelta, String> {
+        self.add_fow_zip_reader(std::io::Cursor::new(zip_data))
+    }
+
+    /// The same as [`FogMap::add_fow_zip`], reading the zip file from `reader`.
+    pub fn add_fow_zip_reader<R: Read + Seek>(
+        &mut self,
+        reader: R,
+    ) -> Result<ExplorationDelta, String> {
         let mut archive = match zip::ZipArchive::new(reader) {
             Ok(archive) => archive,
             Err(e) => return Err(format!("Failed to read zip file: {}", e)),
         };
 
+        let mut delta = ExplorationDelta::new();
         for i in 0..archive.len() {
-            let mut file = match archive.by_index(i) {
+            let file = match archive.by_index(i) {
                 Ok(file) => file,
                 Err(_) => continue,
             };
@@ -109,22 +222,26 @@ impl FogMap {
                 continue;
             }
 
-            let mut buffer = Vec::new();
-            if file.read_to_end(&mut buffer).is_err() {
-                continue;
-            }
-
             // Try to add the FOW file using just the filename part
-            self.add_fow_file(&file_name, buffer);
+            if let Ok(file_delta) = self.add_fow_reader(&file_name, file) {
+                delta.merge(file_delta);
+            }
         }
 
-        Ok(())
+        Ok(delta)
     }
 
     pub fn get_tile(&self, x: i64, y: i64) -> Option<&Tile> {
         self.tiles.get(&(x, y))
     }
 
+    fn get_or_insert_tile(&mut self, key: (i64, i64), delta: &mut ExplorationDelta) -> &mut Tile {
+        self.tiles.entry(key).or_insert_with(|| {
+            delta.new_tiles.insert(key);
+            Tile::new()
+        })
+    }
+
     // Web Mercator projection
     pub fn lng_lat_to_tile_x_y(lng: f64, lat: f64, zoom: i16) -> (i64, i64) {
         let mul = (1 << zoom) as f64;
@@ -133,85 +250,675 @@ impl FogMap {
         (x as i64, y as i64)
     }
 
-    pub fn add_line(&mut self, start_lng: f64, start_lat: f64, end_lng: f64, end_lat: f64) {
-        println!("[{},{}] to [{},{}]", start_lng, start_lat, end_lng, end_lat);
+    /// Marks the single pixel at the given location as visited.
+    pub fn add_point(&mut self, lng: f64, lat: f64) -> ExplorationDelta {
+        let (x, y) = Self::lng_lat_to_pixel(lng, lat);
+        let mut delta = ExplorationDelta::new();
+        self.fill_span(y, x, x, &mut delta);
+        delta
+    }
 
-        let (mut x0, y0) =
-            Self::lng_lat_to_tile_x_y(start_lng, start_lat, ALL_OFFSET + MAP_WIDTH_OFFSET);
-        let (mut x1, y1) =
-            Self::lng_lat_to_tile_x_y(end_lng, end_lat, ALL_OFFSET + MAP_WIDTH_OFFSET);
+    /// Marks every pixel within `radius` meters of the given location as visited.
+    pub fn add_circle(&mut self, lng: f64, lat: f64, radius: f64) -> ExplorationDelta {
+        let mut delta = self.add_point(lng, lat);
+        let (cx, cy) = Self::lng_lat_to_pixel_f64(lng, lat);
+        // the size of a pixel in meters at this latitude, pixels being the smallest at the edges
+        // of the map
+        let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+        let pixel_size = EARTH_CIRCUMFERENCE * lat.to_radians().cos() / PIXEL_MAP_WIDTH as f64;
+        let r = radius / pixel_size;
+        if !r.is_finite() || !cy.is_finite() {
+            return delta;
+        }
+        let y_start = ((cy - r).floor() as i64).max(0);
+        let y_end = ((cy + r).floor() as i64).min(PIXEL_MAP_WIDTH - 1);
+        for y in y_start..=y_end {
+            // fill the pixels whose center is within the circle
+            let dy = y as f64 + 0.5 - cy;
+            if dy.abs() > r {
+                continue;
+            }
+            let half_width = (r * r - dy * dy).sqrt();
+            let start = (cx - half_width - 0.5).ceil() as i64;
+            let end = (cx + half_width - 0.5).floor() as i64;
+            self.fill_span(y, start, end, &mut delta);
+        }
+        delta
+    }
 
-        let (x_half, _) = Self::lng_lat_to_tile_x_y(0.0, 0.0, ALL_OFFSET + MAP_WIDTH_OFFSET);
+    /// Marks every pixel inside a polygon as visited, the polygon being given as rings of
+    /// `(lng, lat)` points, typically an outer ring followed by holes.
+    ///
+    /// Pixels are filled when their center is inside the polygon, according to the even-odd rule.
+    /// Rings may be open or closed. Note that a large polygon makes for a very large number of
+    /// pixels at this resolution.
+    pub fn add_polygon(&mut self, rings: &[Vec<(f64, f64)>]) -> ExplorationDelta {
+        let mut delta = ExplorationDelta::new();
+
+        // the non-horizontal edges in pixels, with y0 < y1
+        let mut edges: Vec<(f64, f64, f64, f64)> = Vec::new();
+        for ring in rings {
+            let mut points: Vec<(f64, f64)> = Vec::with_capacity(ring.len() + 1);
+            for &(lng, lat) in ring {
+                let (mut x, y) = Self::lng_lat_to_pixel_f64(lng, lat);
+                // keep the ring continuous across the antimeridian
+                if let Some(&(last_x, _)) = points.last() {
+                    let half = PIXEL_MAP_WIDTH as f64 / 2.0;
+                    if x - last_x > half {
+                        x -= PIXEL_MAP_WIDTH as f64;
+                    } else if last_x - x > half {
+                        x += PIXEL_MAP_WIDTH as f64;
+                    }
+                }
+                points.push((x, y));
+            }
+            if let Some(&first) = points.first() {
+                points.push(first);
+            }
+            for pair in points.windows(2) {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                if y0 < y1 {
+                    edges.push((x0, y0, x1, y1));
+                } else if y1 < y0 {
+                    edges.push((x1, y1, x0, y0));
+                }
+            }
+        }
+        edges.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let y_max = edges.iter().map(|edge| edge.3).fold(f64::MIN, f64::max);
+        // the rows are kept within the map, for the polygons reaching the poles
+        let y_start = match edges.first() {
+            Some(edge) => (edge.1.floor() as i64).max(0),
+            None => return delta,
+        };
+        let y_end = (y_max.ceil() as i64).min(PIXEL_MAP_WIDTH - 1);
+
+        // scan the rows at the center of the pixels, keeping the edges crossing the row
+        let mut next_edge = 0;
+        let mut active: Vec<(f64, f64, f64, f64)> = Vec::new();
+        let mut crossings: Vec<f64> = Vec::new();
+        for y in y_start..=y_end {
+            let yc = y as f64 + 0.5;
+            while next_edge < edges.len() && edges[next_edge].1 <= yc {
+                active.push(edges[next_edge]);
+                next_edge += 1;
+            }
+            active.retain(|edge| edge.3 > yc);
+
+            crossings.clear();
+            crossings.extend(
+                active
+                    .iter()
+                    .filter(|edge| edge.1 <= yc)
+                    .map(|&(x0, y0, x1, y1)| x0 + (yc - y0) * (x1 - x0) / (y1 - y0)),
+            );
+            crossings.sort_by(f64::total_cmp);
+            for pair in crossings.chunks_exact(2) {
+                let start = (pair[0] - 0.5).ceil() as i64;
+                let end = (pair[1] - 0.5).ceil() as i64 - 1;
+                self.fill_span(y, start, end, &mut delta);
+            }
+        }
+        delta
+    }
+
+    /// Adds a straight track between two points, rasterized with [`LineMode::Bresenham`].
+    pub fn add_line(
+        &mut self,
+        start_lng: f64,
+        start_lat: f64,
+        end_lng: f64,
+        end_lat: f64,
+    ) -> ExplorationDelta {
+        self.add_line_with_mode(start_lng, start_lat, end_lng, end_lat, LineMode::Bresenham)
+    }
+
+    /// Adds a straight track between two points, rasterized according to `mode`.
+    ///
+    /// The error term of the line is carried across block and tile boundaries, so the result
+    /// does not depend on how the line is split over the underlying bitmaps.
+    pub fn add_line_with_mode(
+        &mut self,
+        start_lng: f64,
+        start_lat: f64,
+        end_lng: f64,
+        end_lat: f64,
+        mode: LineMode,
+    ) -> ExplorationDelta {
+        let line = LineSegment::new(
+            Self::lng_lat_to_pixel(start_lng, start_lat),
+            Self::lng_lat_to_pixel(end_lng, end_lat),
+        );
+
+        let mut delta = ExplorationDelta::new();
+        let (mut x, mut y) = line.start;
+        let mut p = line.initial_error(mode);
+        while mode.continues(line.major(x, y), line.end, p) {
+            // tile_x is not rounded, it may exceed the antimeridian
+            let (tile_x, tile_y) = (x >> ALL_OFFSET, y >> ALL_OFFSET);
+            let key = (tile_x.rem_euclid(MAP_WIDTH), tile_y);
+            let mut tile_delta = TileDelta::default();
+            let tile = self.get_or_insert_tile(key, &mut delta);
+            (x, y, p) = line.add_to_tile(tile, tile_x, tile_y, x, y, p, mode, &mut tile_delta);
+            delta.add_tile_delta(key, tile_delta);
+        }
+        delta
+    }
+
+    /// Adds many tracks at once, each given as a sequence of `(lng, lat)` points joined by lines.
+    ///
+    /// This produces the same bitmap as calling [`FogMap::add_line_with_mode`] on every pair of
+    /// consecutive points, but it is much faster for large imports: all points are projected once,
+    /// the segments are split and grouped by tile, and each tile is then rasterized in one go.
+    /// With `parallel` the tiles are distributed over the global rayon thread pool, except on wasm
+    /// or without the `native` feature where a single thread is used.
+    pub fn add_polylines<I, P>(
+        &mut self,
+        polylines: I,
+        mode: LineMode,
+        parallel: bool,
+    ) -> ExplorationDelta
+    where
+        I: IntoIterator<Item = P>,
+        P: IntoIterator<Item = (f64, f64)>,
+    {
+        let mut runs: HashMap<(i64, i64), Vec<TileRun>> = HashMap::new();
+        for polyline in polylines {
+            let points: Vec<(i64, i64)> = polyline
+                .into_iter()
+                .map(|(lng, lat)| Self::lng_lat_to_pixel(lng, lat))
+                .collect();
+            for pair in points.windows(2) {
+                LineSegment::new(pair[0], pair[1]).split_by_tiles(mode, &mut runs);
+            }
+        }
+
+        let mut delta = ExplorationDelta::new();
+        for key in runs.keys() {
+            self.get_or_insert_tile(*key, &mut delta);
+        }
+        let mut jobs: Vec<((i64, i64), &mut Tile, Vec<TileRun>)> = self
+            .tiles
+            .iter_mut()
+            .filter_map(|(key, tile)| runs.remove(key).map(|runs| (*key, tile, runs)))
+            .collect();
+
+        let rasterize = |(key, tile, runs): &mut ((i64, i64), &mut Tile, Vec<TileRun>)| {
+            let mut tile_delta = TileDelta::default();
+            for run in runs.iter() {
+                run.line.add_to_tile(
+                    tile,
+                    run.tile_x,
+                    run.tile_y,
+                    run.x,
+                    run.y,
+                    run.p,
+                    mode,
+                    &mut tile_delta,
+                );
+            }
+            let mut delta = ExplorationDelta::new();
+            delta.add_tile_delta(*key, tile_delta);
+            delta
+        };
+        #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+        if parallel && jobs.len() > 1 {
+            use rayon::prelude::*;
+            delta.merge(jobs.par_iter_mut().map(rasterize).reduce(
+                ExplorationDelta::new,
+                |mut delta, other| {
+                    delta.merge(other);
+                    delta
+                },
+            ));
+            return delta;
+        }
+        #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+        let _ = parallel;
+        for job in jobs.iter_mut() {
+            delta.merge(rasterize(job));
+        }
+        delta
+    }
+
+    /// Moves the visited pixels from the datum `from` to the datum `to`, into a new FogMap.
+    ///
+    /// Each pixel of the result is sampled from the pixel it comes from through the inverse
+    /// transform, so the stretching of the shift does not leave holes between moved pixels, and
+    /// the diagonal steps left where the shift crosses a pixel boundary are filled when the
+    /// pixels they join were 4-connected. Pixels the datums agree on are left as they are.
+    pub fn reproject(&self, from: Datum, to: Datum) -> FogMap {
+        // moves a position in pixels through a datum transform
+        let transform = |(x, y): (f64, f64), from: Datum, to: Datum| {
+            let (lng, lat) = Self::pixel_to_lng_lat(x, y);
+            let (lng, lat) = from.convert(to, lng, lat);
+            (Self::lng_lat_to_pixel_f64(lng, lat), out_of_china(lng, lat))
+        };
+
+        // the blocks of the result, in blocks from the origin of the map
+        let mut targets = HashSet::new();
+        for (&(tile_x, tile_y), tile) in &self.tiles {
+            for ((block_x, block_y), _) in tile.blocks() {
+                let x = (((tile_x << TILE_WIDTH_OFFSET) + block_x) << BITMAP_WIDTH_OFFSET) as f64;
+                let y = (((tile_y << TILE_WIDTH_OFFSET) + block_y) << BITMAP_WIDTH_OFFSET) as f64;
+                let size = BITMAP_WIDTH as f64;
+                let corners = [(x, y), (x + size, y), (x, y + size), (x + size, y + size)]
+                    .map(|corner| transform(corner, from, to));
+                // pixels on both sides of the border of China move apart
+                let margin = if corners.iter().all(|corner| corner.1 == corners[0].1) {
+                    1.0
+                } else {
+                    size
+                };
+                let min_x = corners.iter().map(|c| c.0 .0).fold(f64::MAX, f64::min) - margin;
+                let max_x = corners.iter().map(|c| c.0 .0).fold(f64::MIN, f64::max) + margin;
+                let min_y = corners.iter().map(|c| c.0 .1).fold(f64::MAX, f64::min) - margin;
+                let max_y = corners.iter().map(|c| c.0 .1).fold(f64::MIN, f64::max) + margin;
+                let max_block = (PIXEL_MAP_WIDTH >> BITMAP_WIDTH_OFFSET) - 1;
+                for target_y in (min_y as i64 >> BITMAP_WIDTH_OFFSET).max(0)
+                    ..=(max_y as i64 >> BITMAP_WIDTH_OFFSET).min(max_block)
+                {
+                    for target_x in (min_x.floor() as i64 >> BITMAP_WIDTH_OFFSET)
+                        ..=(max_x.floor() as i64 >> BITMAP_WIDTH_OFFSET)
+                    {
+                        targets.insert((target_x.rem_euclid(max_block + 1), target_y));
+                    }
+                }
+            }
+        }
+
+        let mut result = FogMap::new();
+        let mut delta = ExplorationDelta::new();
+        for (target_x, target_y) in targets {
+            let x = (target_x << BITMAP_WIDTH_OFFSET) as f64;
+            let y = (target_y << BITMAP_WIDTH_OFFSET) as f64;
+            let size = BITMAP_WIDTH as f64;
+            let corners = [(x, y), (x + size, y), (x, y + size), (x + size, y + size)]
+                .map(|corner| transform(corner, to, from));
+            // the transform is smooth enough to be interpolated within a block, except across
+            // the border of China
+            let interpolate = corners.iter().all(|corner| corner.1 == corners[0].1);
+
+            let mut block = Block::new();
+            let mut visited = false;
+            for i in 0..BITMAP_WIDTH {
+                for j in 0..BITMAP_WIDTH {
+                    let (u, v) = ((i as f64 + 0.5) / size, (j as f64 + 0.5) / size);
+                    let (source_x, source_y) = if interpolate {
+                        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+                        let [(c00, _), (c10, _), (c01, _), (c11, _)] = corners;
+                        (
+                            lerp(lerp(c00.0, c10.0, u), lerp(c01.0, c11.0, u), v),
+                            lerp(lerp(c00.1, c10.1, u), lerp(c01.1, c11.1, u), v),
+                        )
+                    } else {
+                        transform((x + u * size, y + v * size), to, from).0
+                    };
+                    if self.is_pixel_visited(source_x.floor() as i64, source_y.floor() as i64) {
+                        block.set_point(i, j, true);
+                        visited = true;
+                    }
+                }
+            }
+            if visited {
+                let key = (target_x >> TILE_WIDTH_OFFSET, target_y >> TILE_WIDTH_OFFSET);
+                let mut tile_delta = TileDelta::default();
+                result.get_or_insert_tile(key, &mut delta).merge_block(
+                    target_x & (TILE_WIDTH - 1),
+                    target_y & (TILE_WIDTH - 1),
+                    &block,
+                    &mut tile_delta,
+                );
+            }
+        }
+
+        // pixels only touching diagonally where the shift crosses a pixel boundary are joined,
+        // when they come from pixels that were 4-connected
+        let source_pixel = |x: i64, y: i64| {
+            let ((x, y), _) = transform((x as f64 + 0.5, y as f64 + 0.5), to, from);
+            (x.floor() as i64, y.floor() as i64)
+        };
+        let mut holes = Vec::new();
+        for (&(tile_x, tile_y), tile) in &result.tiles {
+            for ((block_x, block_y), block) in tile.blocks() {
+                let x0 = (tile_x << ALL_OFFSET) + (block_x << BITMAP_WIDTH_OFFSET);
+                let y0 = (tile_y << ALL_OFFSET) + (block_y << BITMAP_WIDTH_OFFSET);
+                for i in 0..BITMAP_WIDTH {
+                    for j in 0..BITMAP_WIDTH {
+                        if !block.is_visited(i, j) {
+                            continue;
+                        }
+                        let (x, y) = (x0 + i, y0 + j);
+                        for dy in [-1, 1] {
+                            if !result.is_pixel_visited(x + 1, y + dy)
+                                || result.is_pixel_visited(x + 1, y)
+                                || result.is_pixel_visited(x, y + dy)
+                            {
+                                continue;
+                            }
+                            let (source_x, source_y) = source_pixel(x, y);
+                            let (next_x, next_y) = source_pixel(x + 1, y + dy);
+                            let half = PIXEL_MAP_WIDTH / 2;
+                            let sdx = (next_x - source_x + half).rem_euclid(PIXEL_MAP_WIDTH) - half;
+                            let sdy = next_y - source_y;
+                            let connected = match (sdx.abs(), sdy.abs()) {
+                                (0, 0) | (0, 1) | (1, 0) => true,
+                                (1, 1) => {
+                                    self.is_pixel_visited(source_x + sdx, source_y)
+                                        || self.is_pixel_visited(source_x, source_y + sdy)
+                                }
+                                _ => false,
+                            };
+                            if connected {
+                                holes.push((x + 1, y));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for (x, y) in holes {
+            result.fill_span(y, x, x, &mut delta);
+        }
+        result
+    }
+
+    fn is_pixel_visited(&self, x: i64, y: i64) -> bool {
+        if !(0..PIXEL_MAP_WIDTH).contains(&y) {
+            return false;
+        }
+        let x = x.rem_euclid(PIXEL_MAP_WIDTH);
+        let tile = match self.tiles.get(&(x >> ALL_OFFSET, y >> ALL_OFFSET)) {
+            Some(tile) => tile,
+            None => return false,
+        };
+        let block_mask = TILE_WIDTH - 1;
+        match tile.get_block(
+            (x >> BITMAP_WIDTH_OFFSET) & block_mask,
+            (y >> BITMAP_WIDTH_OFFSET) & block_mask,
+        ) {
+            Some(block) => block.is_visited(x & (BITMAP_WIDTH - 1), y & (BITMAP_WIDTH - 1)),
+            None => false,
+        }
+    }
+
+    // the pixel at the highest resolution, which is the one tracks are rasterized at, rounded
+    // down so that the longitudes beyond the antimeridian wrap around.
+    fn lng_lat_to_pixel(lng: f64, lat: f64) -> (i64, i64) {
+        let (x, y) = Self::lng_lat_to_pixel_f64(lng, lat);
+        (x.floor() as i64, y.floor() as i64)
+    }
+
+    // the same as `lng_lat_to_pixel`, without rounding to the pixel.
+    pub(crate) fn lng_lat_to_pixel_f64(lng: f64, lat: f64) -> (f64, f64) {
+        let mul = PIXEL_MAP_WIDTH as f64;
+        let x = (lng + 180.0) / 360.0 * mul;
+        let y = (PI - (lat * PI / 180.0).tan().asinh()) * mul / (2.0 * PI);
+        (x, y)
+    }
+
+    // the inverse of `lng_lat_to_pixel_f64`.
+    fn pixel_to_lng_lat(x: f64, y: f64) -> (f64, f64) {
+        let mul = PIXEL_MAP_WIDTH as f64;
+        let lng = x / mul * 360.0 - 180.0;
+        let lat = (PI - 2.0 * PI * y / mul).sinh().atan().to_degrees();
+        (lng, lat)
+    }
+
+    // marks the pixels from `start` to `end` (inclusive) of the row `y` as visited, wrapping
+    // around the antimeridian.
+    pub(crate) fn fill_span(&mut self, y: i64, start: i64, end: i64, delta: &mut ExplorationDelta) {
+        if !(0..PIXEL_MAP_WIDTH).contains(&y) {
+            return;
+        }
+        let end = end.min(start + PIXEL_MAP_WIDTH - 1);
+        let tile_y = y >> ALL_OFFSET;
+        let mut x = start;
+        while x <= end {
+            let tile_x = x >> ALL_OFFSET;
+            let tile_end = end.min(((tile_x + 1) << ALL_OFFSET) - 1);
+            let key = (tile_x.rem_euclid(MAP_WIDTH), tile_y);
+            let mut tile_delta = TileDelta::default();
+            let tile = self.get_or_insert_tile(key, delta);
+            tile.fill_span(
+                y - (tile_y << ALL_OFFSET),
+                x - (tile_x << ALL_OFFSET),
+                tile_end - (tile_x << ALL_OFFSET),
+                &mut tile_delta,
+            );
+            delta.add_tile_delta(key, tile_delta);
+            x = tile_end + 1;
+        }
+    }
+}
+
+// A line between two pixels, normalized so that its major axis is walked forward.
+#[derive(Debug, Copy, Clone)]
+struct LineSegment {
+    start: (i64, i64),
+    // the major axis coordinate of the end pixel
+    end: i64,
+    dx0: i64,
+    dy0: i64,
+    xaxis: bool,
+    quadrants13: bool,
+}
+
+// Where a line enters a tile, see `LineSegment::split_by_tiles`.
+struct TileRun {
+    line: LineSegment,
+    tile_x: i64,
+    tile_y: i64,
+    x: i64,
+    y: i64,
+    p: i64,
+}
+
+impl LineSegment {
+    fn new((mut x0, y0): (i64, i64), (mut x1, y1): (i64, i64)) -> Self {
+        let (x_half, _) = FogMap::lng_lat_to_pixel(0.0, 0.0);
         if x1 - x0 > x_half {
             x0 += 2 * x_half;
         } else if x0 - x1 > x_half {
             x1 += 2 * x_half;
         }
 
-        // Iterators, counters required by algorithm
         // Calculate line deltas
         let dx = x1 - x0;
         let dy = y1 - y0;
         // Create a positive copy of deltas (makes iterating easier)
         let dx0 = dx.abs();
         let dy0 = dy.abs();
-        // Calculate error intervals for both axis
-        let mut px = 2 * dy0 - dx0;
-        let mut py = 2 * dx0 - dy0;
-        // The line is X-axis dominant
+        let quadrants13 = (dx < 0 && dy < 0) || (dx > 0 && dy > 0);
         if dy0 <= dx0 {
-            let (mut x, mut y, xe) = if dx >= 0 {
+            // The line is X-axis dominant
+            if dx >= 0 {
                 // Line is drawn left to right
-                (x0, y0, x1)
+                Self::with_axis((x0, y0), x1, dx0, dy0, true, quadrants13)
             } else {
                 // Line is drawn right to left (swap ends)
-                (x1, y1, x0)
-            };
-            while x < xe {
-                // tile_x is not rounded, it may exceed the antimeridian
-                let (tile_x, tile_y) = (x >> ALL_OFFSET, y >> ALL_OFFSET);
-                let tile = self.tiles.entry((tile_x % MAP_WIDTH, tile_y)).or_default();
-                (x, y, px) = tile.add_line(
-                    x - (tile_x << ALL_OFFSET),
-                    y - (tile_y << ALL_OFFSET),
-                    xe - (tile_x << ALL_OFFSET),
-                    px,
-                    dx0,
-                    dy0,
-                    true,
-                    (dx < 0 && dy < 0) || (dx > 0 && dy > 0),
-                );
-                x += tile_x << ALL_OFFSET;
-                y += tile_y << ALL_OFFSET;
+                Self::with_axis((x1, y1), x0, dx0, dy0, true, quadrants13)
             }
         } else {
             // The line is Y-axis dominant
-            let (mut x, mut y, ye) = if dy >= 0 {
+            if dy >= 0 {
                 // Line is drawn bottom to top
-                (x0, y0, y1)
+                Self::with_axis((x0, y0), y1, dx0, dy0, false, quadrants13)
             } else {
                 // Line is drawn top to bottom
-                (x1, y1, y0)
-            };
-            while y < ye {
-                // tile_x is not rounded, it may exceed the antimeridian
-                let (tile_x, tile_y) = (x >> ALL_OFFSET, y >> ALL_OFFSET);
-                let tile = self.tiles.entry((tile_x % MAP_WIDTH, tile_y)).or_default();
-                (x, y, py) = tile.add_line(
-                    x - (tile_x << ALL_OFFSET),
-                    y - (tile_y << ALL_OFFSET),
-                    ye - (tile_y << ALL_OFFSET),
-                    py,
-                    dx0,
-                    dy0,
-                    false,
-                    (dx < 0 && dy < 0) || (dx > 0 && dy > 0),
-                );
-                x += tile_x << ALL_OFFSET;
-                y += tile_y << ALL_OFFSET;
+                Self::with_axis((x1, y1), y0, dx0, dy0, false, quadrants13)
             }
         }
     }
+
+    fn with_axis(
+        start: (i64, i64),
+        end: i64,
+        dx0: i64,
+        dy0: i64,
+        xaxis: bool,
+        quadrants13: bool,
+    ) -> Self {
+        Self {
+            start,
+            end,
+            dx0,
+            dy0,
+            xaxis,
+            quadrants13,
+        }
+    }
+
+    fn major(&self, x: i64, y: i64) -> i64 {
+        if self.xaxis {
+            x
+        } else {
+            y
+        }
+    }
+
+    // the length of the line along its major and minor axis.
+    fn lengths(&self) -> (i64, i64) {
+        if self.xaxis {
+            (self.dx0, self.dy0)
+        } else {
+            (self.dy0, self.dx0)
+        }
+    }
+
+    fn initial_error(&self, mode: LineMode) -> i64 {
+        let (major, minor) = self.lengths();
+        mode.initial_error(major, minor)
+    }
+
+    // the pixel `i` steps along the major axis and `m` steps along the minor axis from the start.
+    fn pixel(&self, i: i64, m: i64) -> (i64, i64) {
+        let m = if self.quadrants13 { m } else { -m };
+        if self.xaxis {
+            (self.start.0 + i, self.start.1 + m)
+        } else {
+            (self.start.0 + m, self.start.1 + i)
+        }
+    }
+
+    // rasterizes the line from (`x`, `y`) until it leaves the tile, in the same way as
+    // `FogMap::add_line_with_mode`.
+    #[allow(clippy::too_many_arguments)]
+    fn add_to_tile(
+        &self,
+        tile: &mut Tile,
+        tile_x: i64,
+        tile_y: i64,
+        x: i64,
+        y: i64,
+        p: i64,
+        mode: LineMode,
+        delta: &mut TileDelta,
+    ) -> (i64, i64, i64) {
+        let tile_major = if self.xaxis { tile_x } else { tile_y };
+        let (x, y, p) = tile.add_line(
+            x - (tile_x << ALL_OFFSET),
+            y - (tile_y << ALL_OFFSET),
+            self.end - (tile_major << ALL_OFFSET),
+            p,
+            self.dx0,
+            self.dy0,
+            self.xaxis,
+            self.quadrants13,
+            mode,
+            delta,
+        );
+        (x + (tile_x << ALL_OFFSET), y + (tile_y << ALL_OFFSET), p)
+    }
+
+    // Splits the line into the pieces `FogMap::add_line_with_mode` would rasterize tile by tile,
+    // without walking the pixels: the state the rasterizer has when entering a tile is computed
+    // in closed form from the number of steps taken along each axis.
+    fn split_by_tiles(&self, mode: LineMode, runs: &mut HashMap<(i64, i64), Vec<TileRun>>) {
+        let (n_major, n_minor) = self.lengths();
+        // the position of the current pixel, in steps along the major and minor axis
+        let (mut i, mut m) = (0, 0);
+        let mut p = self.initial_error(mode);
+        loop {
+            let (x, y) = self.pixel(i, m);
+            if !mode.continues(self.major(x, y), self.end, p) {
+                break;
+            }
+            let (tile_x, tile_y) = (x >> ALL_OFFSET, y >> ALL_OFFSET);
+            runs.entry((tile_x.rem_euclid(MAP_WIDTH), tile_y))
+                .or_default()
+                .push(TileRun {
+                    line: *self,
+                    tile_x,
+                    tile_y,
+                    x,
+                    y,
+                    p,
+                });
+
+            // the first step along each axis that leaves the tile
+            let (tile_major, tile_minor, minor) = if self.xaxis {
+                (tile_x, tile_y, y)
+            } else {
+                (tile_y, tile_x, x)
+            };
+            let i_exit = ((tile_major + 1) << ALL_OFFSET) - self.major(self.start.0, self.start.1);
+            let m_exit = if self.quadrants13 {
+                ((tile_minor + 1) << ALL_OFFSET) - minor + m
+            } else {
+                minor - (tile_minor << ALL_OFFSET) + 1 + m
+            };
+
+            // the first pixel of the line outside the tile
+            (i, m) = match mode {
+                LineMode::Bresenham => {
+                    // after `k` major steps the line took `minor_steps(k)` minor steps
+                    let minor_steps = |k: i64| {
+                        if self.xaxis {
+                            (2 * n_minor * k + n_major).div_euclid(2 * n_major)
+                        } else {
+                            -(-(2 * n_minor * k - n_major)).div_euclid(2 * n_major)
+                        }
+                    };
+                    let mut k = i_exit;
+                    if n_minor > 0 {
+                        let bound = (2 * m_exit - 1) * n_major;
+                        let k_minor = if self.xaxis {
+                            -(-bound).div_euclid(2 * n_minor)
+                        } else {
+                            bound.div_euclid(2 * n_minor) + 1
+                        };
+                        k = k.min(k_minor);
+                    }
+                    let k = k.min(n_major);
+                    (k, minor_steps(k))
+                }
+                LineMode::Supercover => {
+                    // the first pixel at `j` major steps took `first_minor(j)` minor steps
+                    let first_minor = |j: i64| {
+                        if j == 0 {
+                            0
+                        } else {
+                            -(-((2 * j - 1) * n_minor - n_major)).div_euclid(2 * n_major)
+                        }
+                    };
+                    let mut exit = (n_major, n_minor);
+                    if i_exit <= n_major {
+                        exit = exit.min((i_exit, first_minor(i_exit)));
+                    }
+                    if m_exit <= n_minor {
+                        let j = ((2 * m_exit - 1) * n_major + n_minor).div_euclid(2 * n_minor);
+                        exit = exit.min((j.min(n_major), m_exit));
+                    }
+                    exit
+                }
+            };
+            p = match mode {
+                LineMode::Bresenham => 2 * n_minor * (i + 1) - n_major - 2 * m * n_major,
+                LineMode::Supercover => (1 + 2 * i) * n_minor - (1 + 2 * m) * n_major,
+            };
+        }
+    }
 }
 
 pub struct Tile {
@@ -235,21 +942,15 @@ impl Tile {
         }
     }
 
-    fn add_by_blocks(&mut self, x: i64, y: i64, block: Block) {
-        // TODO: current implementation will replace the tile if exist, change it to additive editing.
-        // TODO: rethink the data type and whether should use into()
-        let index = (x << TILE_WIDTH_OFFSET) + y;
-        if self.blocks_key[index as usize] == -1 {
-            self.blocks_key[index as usize] = self.blocks_buffer.len() as i16;
-            self.blocks_buffer.push(Some(block));
-        } else {
-            self.blocks_buffer[self.blocks_key[index as usize] as usize] = Some(block);
-        }
+    // adds the points of `block` to the block `(x, y)`, keeping the points already there.
+    fn merge_block(&mut self, x: i64, y: i64, block: &Block, delta: &mut TileDelta) {
+        delta.new_pixels += self.get_or_insert_block(x, y, delta).merge(block);
     }
 
-    fn get_or_insert_block(&mut self, x: i64, y: i64) -> &mut Block {
+    fn get_or_insert_block(&mut self, x: i64, y: i64, delta: &mut TileDelta) -> &mut Block {
         let index = (x << TILE_WIDTH_OFFSET) + y;
         if self.blocks_key[index as usize] == -1 {
+            delta.new_blocks.push((x, y));
             self.blocks_key[index as usize] = self.blocks_buffer.len() as i16;
             self.blocks_buffer.push(Some(Block::new()));
         }
@@ -258,6 +959,39 @@ impl Tile {
             .unwrap()
     }
 
+    // marks the points from `start` to `end` (inclusive) of the row `y` as visited.
+    fn fill_span(&mut self, y: i64, start: i64, end: i64, delta: &mut TileDelta) {
+        let block_y = y >> BITMAP_WIDTH_OFFSET;
+        let mut x = start;
+        while x <= end {
+            let block_x = x >> BITMAP_WIDTH_OFFSET;
+            let block_end = end.min(((block_x + 1) << BITMAP_WIDTH_OFFSET) - 1);
+            let block = self.get_or_insert_block(block_x, block_y, delta);
+            delta.new_pixels += block.fill_span(
+                y - (block_y << BITMAP_WIDTH_OFFSET),
+                x - (block_x << BITMAP_WIDTH_OFFSET),
+                block_end - (block_x << BITMAP_WIDTH_OFFSET),
+            );
+            x = block_end + 1;
+        }
+    }
+
+    /// The blocks of the tile, with their coordinates in the tile.
+    pub fn blocks(&self) -> impl Iterator<Item = ((i64, i64), &Block)> {
+        self.blocks_key
+            .iter()
+            .enumerate()
+            .filter(|(_, &key)| key >= 0)
+            .filter_map(move |(index, &key)| {
+                let block = self.blocks_buffer[key as usize].as_ref()?;
+                let index = index as i64;
+                Some((
+                    (index >> TILE_WIDTH_OFFSET, index & (TILE_WIDTH - 1)),
+                    block,
+                ))
+            })
+    }
+
     pub fn get_block(&self, x: i64, y: i64) -> Option<&Block> {
         let index = (x << TILE_WIDTH_OFFSET) + y;
         if self.blocks_key[index as usize] == -1 {
@@ -278,13 +1012,15 @@ impl Tile {
         dy0: i64,
         xaxis: bool,
         quadrants13: bool,
+        mode: LineMode,
+        delta: &mut TileDelta,
     ) -> (i64, i64, i64) {
         let mut p = p;
         let mut x = x;
         let mut y = y;
         if xaxis {
             // Rasterize the line
-            while x < e {
+            while mode.continues(x, e, p) {
                 if x >> BITMAP_WIDTH_OFFSET >= TILE_WIDTH
                     || y >> BITMAP_WIDTH_OFFSET < 0
                     || y >> BITMAP_WIDTH_OFFSET >= TILE_WIDTH
@@ -294,7 +1030,7 @@ impl Tile {
                 let block_x = x >> BITMAP_WIDTH_OFFSET;
                 let block_y = y >> BITMAP_WIDTH_OFFSET;
 
-                let block = self.get_or_insert_block(block_x, block_y);
+                let block = self.get_or_insert_block(block_x, block_y, delta);
                 (x, y, p) = block.add_line(
                     x - (block_x << BITMAP_WIDTH_OFFSET),
                     y - (block_y << BITMAP_WIDTH_OFFSET),
@@ -304,6 +1040,8 @@ impl Tile {
                     dy0,
                     xaxis,
                     quadrants13,
+                    mode,
+                    &mut delta.new_pixels,
                 );
 
                 x += block_x << BITMAP_WIDTH_OFFSET;
@@ -311,7 +1049,7 @@ impl Tile {
             }
         } else {
             // Rasterize the line
-            while y < e {
+            while mode.continues(y, e, p) {
                 if y >> BITMAP_WIDTH_OFFSET >= TILE_WIDTH
                     || x >> BITMAP_WIDTH_OFFSET < 0
                     || x >> BITMAP_WIDTH_OFFSET >= TILE_WIDTH
@@ -321,7 +1059,7 @@ impl Tile {
                 let block_x = x >> BITMAP_WIDTH_OFFSET;
                 let block_y = y >> BITMAP_WIDTH_OFFSET;
 
-                let block = self.get_or_insert_block(block_x, block_y);
+                let block = self.get_or_insert_block(block_x, block_y, delta);
                 (x, y, p) = block.add_line(
                     x - (block_x << BITMAP_WIDTH_OFFSET),
                     y - (block_y << BITMAP_WIDTH_OFFSET),
@@ -331,6 +1069,8 @@ impl Tile {
                     dy0,
                     xaxis,
                     quadrants13,
+                    mode,
+                    &mut delta.new_pixels,
                 );
 
                 x += block_x << BITMAP_WIDTH_OFFSET;
@@ -364,13 +1104,58 @@ impl Block {
         (self.data[i + j * 8] & (1 << bit_offset)) != 0
     }
 
-    fn set_point(&mut self, x: i64, y: i64, val: bool) {
+    // returns whether the point has changed
+    fn set_point(&mut self, x: i64, y: i64, val: bool) -> bool {
         let bit_offset = 7 - (x % 8);
         let i = (x / 8) as usize;
         let j = (y) as usize;
         let val_number = if val { 1 } else { 0 };
-        self.data[i + j * 8] =
-            (self.data[i + j * 8] & !(1 << bit_offset)) | (val_number << bit_offset);
+        let old = self.data[i + j * 8];
+        self.data[i + j * 8] = (old & !(1 << bit_offset)) | (val_number << bit_offset);
+        self.data[i + j * 8] != old
+    }
+
+    // the bitmap of the points, a bit per point with the rows one after the other.
+    #[cfg(feature = "native")]
+    pub(crate) fn bitmap(&self) -> &[u8] {
+        &self.data[..BLOCK_BITMAP_SIZE]
+    }
+
+    /// Number of visited points in the block.
+    pub fn count_visited(&self) -> u64 {
+        self.data[..BLOCK_BITMAP_SIZE]
+            .iter()
+            .map(|byte| byte.count_ones() as u64)
+            .sum()
+    }
+
+    // marks the points from `start` to `end` (inclusive) of the row `y` as visited, returns the
+    // number of newly visited points.
+    fn fill_span(&mut self, y: i64, start: i64, end: i64) -> u64 {
+        let mut new_points = 0;
+        for i in (start / 8)..=(end / 8) {
+            // the bits of the byte within the span, the leftmost point being the highest bit
+            let first = std::cmp::max(start - i * 8, 0);
+            let last = std::cmp::min(end - i * 8, 7);
+            let mask = (0xffu8 >> first) & (0xffu8 << (7 - last));
+            let byte = &mut self.data[i as usize + (y as usize) * 8];
+            new_points += (mask & !*byte).count_ones() as u64;
+            *byte |= mask;
+        }
+        new_points
+    }
+
+    // marks every point visited in `other` as visited, returns the number of newly visited points.
+    fn merge(&mut self, other: &Block) -> u64 {
+        let mut new_points = 0;
+        for (byte, other) in self.data[..BLOCK_BITMAP_SIZE]
+            .iter_mut()
+            .zip(&other.data[..BLOCK_BITMAP_SIZE])
+        {
+            new_points += (other & !*byte).count_ones() as u64;
+            *byte |= other;
+        }
+        new_points
     }
 
     // a modified Bresenham algorithm with initialized error from upper layer
@@ -385,6 +1170,8 @@ impl Block {
         dy0: i64,
         xaxis: bool,
         quadrants13: bool,
+        mode: LineMode,
+        new_points: &mut u64,
     ) -> (i64, i64, i64) {
         // println!(
         //     "subblock draw: x:{}, y:{}, e:{}, p:{}, dx0:{}, dy0:{}, xaxis:{}, quadrants13:{}",
@@ -394,21 +1181,39 @@ impl Block {
         let mut p = p;
         let mut x = x;
         let mut y = y;
-        self.set_point(x, y, true);
+        *new_points += self.set_point(x, y, true) as u64;
         if xaxis {
             // Rasterize the line
-            while x < e {
-                x += 1;
-                // Deal with octants...
-                if p < 0 {
-                    p += 2 * dy0;
-                } else {
-                    if quadrants13 {
-                        y += 1;
-                    } else {
-                        y -= 1;
+            while mode.continues(x, e, p) {
+                match mode {
+                    LineMode::Bresenham => {
+                        x += 1;
+                        // Deal with octants...
+                        if p < 0 {
+                            p += 2 * dy0;
+                        } else {
+                            if quadrants13 {
+                                y += 1;
+                            } else {
+                                y -= 1;
+                            }
+                            p += 2 * (dy0 - dx0);
+                        }
+                    }
+                    LineMode::Supercover => {
+                        // A single step along either axis, never both.
+                        if p <= 0 {
+                            x += 1;
+                            p += 2 * dy0;
+                        } else {
+                            if quadrants13 {
+                                y += 1;
+                            } else {
+                                y -= 1;
+                            }
+                            p -= 2 * dx0;
+                        }
                     }
-                    p += 2 * (dy0 - dx0);
                 }
 
                 if x >= BITMAP_WIDTH || !(0..BITMAP_WIDTH).contains(&y) {
@@ -416,23 +1221,41 @@ impl Block {
                 }
                 // Draw pixel from line span at
                 // currently rasterized position
-                self.set_point(x, y, true);
+                *new_points += self.set_point(x, y, true) as u64;
             }
         } else {
             // The line is Y-axis dominant
             // Rasterize the line
-            while y < e {
-                y += 1;
-                // Deal with octants...
-                if p <= 0 {
-                    p += 2 * dx0;
-                } else {
-                    if quadrants13 {
-                        x += 1;
-                    } else {
-                        x -= 1;
+            while mode.continues(y, e, p) {
+                match mode {
+                    LineMode::Bresenham => {
+                        y += 1;
+                        // Deal with octants...
+                        if p <= 0 {
+                            p += 2 * dx0;
+                        } else {
+                            if quadrants13 {
+                                x += 1;
+                            } else {
+                                x -= 1;
+                            }
+                            p += 2 * (dx0 - dy0);
+                        }
+                    }
+                    LineMode::Supercover => {
+                        // A single step along either axis, never both.
+                        if p <= 0 {
+                            y += 1;
+                            p += 2 * dx0;
+                        } else {
+                            if quadrants13 {
+                                x += 1;
+                            } else {
+                                x -= 1;
+                            }
+                            p -= 2 * dy0;
+                        }
                     }
-                    p += 2 * (dx0 - dy0);
                 }
 
                 if y >= BITMAP_WIDTH || !(0..BITMAP_WIDTH).contains(&x) {
@@ -440,7 +1263,7 @@ impl Block {
                 }
                 // Draw pixel from line span at
                 // currently rasterized position
-                self.set_point(x, y, true);
+                *new_points += self.set_point(x, y, true) as u64;
             }
         }
         (x, y, p)
@@ -451,10 +1274,307 @@ impl Block {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use std::collections::HashSet;
+
+    fn visited_pixels(fogmap: &FogMap) -> HashSet<(i64, i64)> {
+        let mut pixels = HashSet::new();
+        for (&(tile_x, tile_y), tile) in &fogmap.tiles {
+            for block_x in 0..TILE_WIDTH {
+                for block_y in 0..TILE_WIDTH {
+                    if let Some(block) = tile.get_block(block_x, block_y) {
+                        for x in 0..BITMAP_WIDTH {
+                            for y in 0..BITMAP_WIDTH {
+                                if block.is_visited(x, y) {
+                                    pixels.insert((
+                                        (tile_x << ALL_OFFSET)
+                                            + (block_x << BITMAP_WIDTH_OFFSET)
+                                            + x,
+                                        (tile_y << ALL_OFFSET)
+                                            + (block_y << BITMAP_WIDTH_OFFSET)
+                                            + y,
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        pixels
+    }
+
+    fn is_4_connected(pixels: &HashSet<(i64, i64)>) -> bool {
+        let start = match pixels.iter().next() {
+            Some(&start) => start,
+            None => return true,
+        };
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some((x, y)) = stack.pop() {
+            for next in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+                if pixels.contains(&next) && seen.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        seen.len() == pixels.len()
+    }
+
+    #[test]
+    fn test_reproject() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_circle(121.48, 31.24, 200.0);
+        // not moved outside of China
+        fogmap.add_point(2.35, 48.85);
+        let moved = visited_pixels(&fogmap.reproject(Datum::Wgs84, Datum::Gcj02));
+        let mut expected = FogMap::new();
+        let (lng, lat) = Datum::Wgs84.convert(Datum::Gcj02, 121.48, 31.24);
+        expected.add_circle(lng, lat, 200.0);
+        expected.add_point(2.35, 48.85);
+        let expected = visited_pixels(&expected);
+        // the circle moved to where it is drawn in GCJ-02, up to rounding on its edge
+        assert!(expected.symmetric_difference(&moved).count() * 10 < expected.len());
+        assert!(moved.contains(&FogMap::lng_lat_to_pixel(2.35, 48.85)));
+
+        // a line stays a line, without holes, and comes back where it was
+        let mut fogmap = FogMap::new();
+        fogmap.add_line_with_mode(121.47, 31.23, 121.49, 31.22, LineMode::Supercover);
+        let moved = fogmap.reproject(Datum::Wgs84, Datum::Gcj02);
+        let line = visited_pixels(&moved);
+        assert!(line.len() > 100);
+        assert!(is_4_connected(&line));
+        let original = visited_pixels(&fogmap);
+        assert!(line.is_disjoint(&original));
+        let back = visited_pixels(&moved.reproject(Datum::Gcj02, Datum::Wgs84));
+        assert!(original.symmetric_difference(&back).count() * 10 < original.len());
+
+        // nothing changes where the datums agree, diagonal steps included
+        let mut fogmap = FogMap::new();
+        fogmap.add_line(2.35, 48.85, 2.37, 48.86);
+        fogmap.add_line(121.47, 31.23, 121.49, 31.22);
+        let original = visited_pixels(&fogmap);
+        assert!(!is_4_connected(&original));
+        assert_eq!(
+            visited_pixels(&fogmap.reproject(Datum::Wgs84, Datum::Wgs84)),
+            original
+        );
+        let mut paris = FogMap::new();
+        paris.add_line(2.35, 48.85, 2.37, 48.86);
+        assert_eq!(
+            visited_pixels(&paris.reproject(Datum::Wgs84, Datum::Gcj02)),
+            visited_pixels(&paris)
+        );
+    }
 
     #[test]
     fn test_add_line() {
         let mut fogmap = FogMap::new();
         fogmap.add_line(121.5157559, 31.29735617, 121.515725, 31.29731979);
     }
+
+    #[test]
+    fn test_add_line_supercover() {
+        // the lines cross several blocks, the first two also cross the boundary between two tiles
+        let lines = [
+            (121.63, 31.20, 121.65, 31.215),
+            (121.65, 31.20, 121.63, 31.26),
+            (121.6405, 31.23, 121.6401, 31.2296),
+        ];
+        for (start_lng, start_lat, end_lng, end_lat) in lines {
+            let mut bresenham = FogMap::new();
+            bresenham.add_line(start_lng, start_lat, end_lng, end_lat);
+            let bresenham = visited_pixels(&bresenham);
+            assert!(!is_4_connected(&bresenham));
+
+            let mut supercover = FogMap::new();
+            supercover.add_line_with_mode(
+                start_lng,
+                start_lat,
+                end_lng,
+                end_lat,
+                LineMode::Supercover,
+            );
+            let supercover = visited_pixels(&supercover);
+            assert!(is_4_connected(&supercover));
+            assert!(supercover.len() > bresenham.len());
+        }
+    }
+
+    #[test]
+    fn test_add_polylines() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut polylines = Vec::new();
+        // around a corner shared by four tiles, and across the antimeridian
+        for (lng, lat) in [(121.640625, 31.35), (179.99, -16.5)] {
+            for _ in 0..8 {
+                let polyline: Vec<(f64, f64)> = (0..10)
+                    .map(|_| {
+                        let lng: f64 = lng + rng.gen_range(-0.1..0.1);
+                        let lng = if lng > 180.0 { lng - 360.0 } else { lng };
+                        (lng, lat + rng.gen_range(-0.1..0.1))
+                    })
+                    .collect();
+                polylines.push(polyline);
+            }
+        }
+
+        for mode in [LineMode::Bresenham, LineMode::Supercover] {
+            let mut expected = FogMap::new();
+            for polyline in &polylines {
+                for pair in polyline.windows(2) {
+                    let ((start_lng, start_lat), (end_lng, end_lat)) = (pair[0], pair[1]);
+                    expected.add_line_with_mode(start_lng, start_lat, end_lng, end_lat, mode);
+                }
+            }
+            let expected_pixels = visited_pixels(&expected);
+
+            for parallel in [false, true] {
+                let mut fogmap = FogMap::new();
+                fogmap.add_polylines(polylines.iter().cloned(), mode, parallel);
+                let mut tiles: Vec<_> = fogmap.tiles.keys().collect();
+                tiles.sort();
+                let mut expected_tiles: Vec<_> = expected.tiles.keys().collect();
+                expected_tiles.sort();
+                assert_eq!(tiles, expected_tiles);
+                assert!(visited_pixels(&fogmap) == expected_pixels);
+            }
+        }
+    }
+
+    #[test]
+    fn test_exploration_delta() {
+        let mut fogmap = FogMap::new();
+        let delta = fogmap.add_line(121.63, 31.20, 121.65, 31.215);
+        assert_eq!(delta.new_pixels, visited_pixels(&fogmap).len() as u64);
+        assert_eq!(delta.new_tiles.len(), 2);
+        assert_eq!(delta.touched_tiles, delta.new_tiles);
+        assert!(delta.new_blocks.len() > 2);
+
+        // drawing over the same pixels again does not explore anything
+        let delta = fogmap.add_line(121.63, 31.20, 121.65, 31.215);
+        assert!(delta.is_empty());
+        assert!(delta.new_tiles.is_empty() && delta.new_blocks.is_empty());
+        assert_eq!(delta.touched_tiles.len(), 2);
+
+        let delta = fogmap.add_polylines(
+            [[(121.63, 31.20), (121.65, 31.215), (121.65, 31.25)]],
+            LineMode::Supercover,
+            false,
+        );
+        let pixels = visited_pixels(&fogmap).len() as u64;
+        let mut expected = FogMap::new();
+        expected.add_line(121.63, 31.20, 121.65, 31.215);
+        assert_eq!(
+            delta.new_pixels,
+            pixels - visited_pixels(&expected).len() as u64
+        );
+        assert!(delta.new_tiles.is_empty());
+    }
+
+    #[test]
+    fn test_exploration_delta_fow_file() {
+        let data = std::fs::read("tests/0921iihwtxn").unwrap();
+        let mut fogmap = FogMap::new();
+        let delta = fogmap.add_fow_file("0921iihwtxn", data.clone());
+        assert_eq!(delta.new_tiles.len(), 1);
+        assert!(!delta.new_blocks.is_empty());
+        assert_eq!(delta.new_pixels, visited_pixels(&fogmap).len() as u64);
+
+        // importing the same file again keeps the tracks and adds nothing
+        let delta = fogmap.add_fow_file("0921iihwtxn", data);
+        assert!(delta.is_empty());
+        assert!(delta.new_blocks.is_empty());
+        assert_eq!(delta.touched_tiles.len(), 1);
+
+        // the blocks of the file are added to the existing ones
+        let (tile_x, tile_y) = FogMap::parse_fow_file_name("0921iihwtxn").unwrap();
+        let ((block_x, block_y), block) = fogmap.tiles[&(tile_x, tile_y)].blocks().next().unwrap();
+        let (i, j) = (0..BITMAP_WIDTH * BITMAP_WIDTH)
+            .map(|k| (k % BITMAP_WIDTH, k / BITMAP_WIDTH))
+            .find(|&(i, j)| !block.is_visited(i, j))
+            .unwrap();
+        let x = (((tile_x << TILE_WIDTH_OFFSET) + block_x) << BITMAP_WIDTH_OFFSET) + i;
+        let y = (((tile_y << TILE_WIDTH_OFFSET) + block_y) << BITMAP_WIDTH_OFFSET) + j;
+        let mut merged = FogMap::new();
+        merged.fill_span(y, x, x, &mut ExplorationDelta::new());
+        let delta = merged.add_fow_file("0921iihwtxn", std::fs::read("tests/0921iihwtxn").unwrap());
+        assert_eq!(delta.new_pixels, visited_pixels(&fogmap).len() as u64);
+        let mut expected = visited_pixels(&fogmap);
+        expected.insert((x, y));
+        assert_eq!(visited_pixels(&merged), expected);
+    }
+
+    #[test]
+    fn test_add_polygon() {
+        // a square across four tiles, with a square hole in its middle
+        let (lng, lat) = (121.640625, 31.3536);
+        let square = |size: f64| {
+            vec![
+                (lng - size, lat - size),
+                (lng + size, lat - size),
+                (lng + size, lat + size),
+                (lng - size, lat + size),
+            ]
+        };
+        let mut fogmap = FogMap::new();
+        let delta = fogmap.add_polygon(&[square(0.002), square(0.001)]);
+        assert_eq!(delta.new_tiles.len(), 4);
+        let pixels = visited_pixels(&fogmap);
+        assert_eq!(delta.new_pixels, pixels.len() as u64);
+
+        let (x0, y0) = FogMap::lng_lat_to_pixel(lng - 0.002, lat + 0.002);
+        let (x1, y1) = FogMap::lng_lat_to_pixel(lng + 0.002, lat - 0.002);
+        let (hole_x0, hole_y0) = FogMap::lng_lat_to_pixel(lng - 0.001, lat + 0.001);
+        let (hole_x1, hole_y1) = FogMap::lng_lat_to_pixel(lng + 0.001, lat - 0.001);
+        let area = (x1 - x0) * (y1 - y0) - (hole_x1 - hole_x0) * (hole_y1 - hole_y0);
+        assert!((pixels.len() as i64 - area).abs() < (x1 - x0) * 4);
+        assert!(pixels.contains(&(x0 + 2, y0 + 2)));
+        assert!(!pixels.contains(&FogMap::lng_lat_to_pixel(lng, lat)));
+        assert!(!pixels.contains(&(x1 + 2, y1 + 2)));
+
+        // filling it again adds nothing
+        assert!(fogmap
+            .add_polygon(&[square(0.002), square(0.001)])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_add_circle() {
+        let mut fogmap = FogMap::new();
+        let delta = fogmap.add_circle(121.5, 31.2, 100.0);
+        let pixel_size = EARTH_CIRCUMFERENCE * 31.2f64.to_radians().cos() / PIXEL_MAP_WIDTH as f64;
+        let expected = PI * (100.0 / pixel_size).powi(2);
+        assert!((delta.new_pixels as f64 - expected).abs() < expected * 0.05);
+        assert_eq!(delta.new_pixels, visited_pixels(&fogmap).len() as u64);
+
+        // a radius smaller than a pixel still visits the location
+        assert_eq!(FogMap::new().add_circle(121.5, 31.2, 0.1).new_pixels, 1);
+
+        // beyond the edges of the map
+        assert!(FogMap::new().add_circle(0.0, 90.0, 100.0).is_empty());
+        let delta = FogMap::new().add_circle(0.0, 85.0, 100.0);
+        assert!(!delta.is_empty() && delta.new_pixels < expected as u64 * 100);
+    }
+
+    #[test]
+    fn test_wrap_longitude() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_point(-190.0, 31.2);
+        fogmap.add_line(-181.0, 31.2, -179.0, 31.21);
+        fogmap.add_polylines(
+            [vec![(-200.0, 31.2), (-199.9, 31.3)]],
+            LineMode::Bresenham,
+            false,
+        );
+        assert!(fogmap
+            .tiles
+            .keys()
+            .all(|&(tile_x, _)| (0..MAP_WIDTH).contains(&tile_x)));
+
+        let (mut east, mut west) = (FogMap::new(), FogMap::new());
+        east.add_point(170.0, 31.2);
+        west.add_point(-190.0, 31.2);
+        assert_eq!(visited_pixels(&east), visited_pixels(&west));
+    }
 }
@@ -0,0 +1,266 @@
+//! Import of CSV tracks, as written by custom loggers or exported from spreadsheets.
+//!
+//! The columns holding the coordinates, the time and the track are given by [`CsvOptions`]. A
+//! row that cannot be read is reported in the summary and counted as a dropped point.
+
+use super::filter::FilterOptions;
+use super::{parse_time, GapOptions, ImportSummary, TrackPoint, TrackWriter};
+use crate::datum::Datum;
+use crate::FogMap;
+use chrono::{DateTime, NaiveDateTime};
+use std::io::Read;
+
+/// A column, by its name in the header row or by its index from 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvColumn {
+    Name(String),
+    Index(usize),
+}
+
+impl CsvColumn {
+    fn resolve(&self, headers: Option<&csv::StringRecord>) -> Result<usize, String> {
+        match self {
+            CsvColumn::Index(index) => Ok(*index),
+            CsvColumn::Name(name) => headers
+                .ok_or_else(|| {
+                    format!("Column '{}' given by name but the CSV has no header", name)
+                })?
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("No column '{}' in the CSV header", name)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum CoordinateFormat {
+    /// Decimal degrees, such as `31.2`.
+    #[default]
+    Degrees,
+    /// Integers of degrees times 10^7, such as `312000000`.
+    E7,
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub lat: CsvColumn,
+    pub lng: CsvColumn,
+    /// The column of the time of the points, if any.
+    pub time: Option<CsvColumn>,
+    /// A `chrono` format string for the time, such as `%d/%m/%Y %H:%M:%S`. Times without a
+    /// time zone are taken as UTC. When not set, the time is either RFC 3339 or a Unix
+    /// timestamp in seconds.
+    pub time_format: Option<String>,
+    /// The column identifying the track of each row. Tracks are split where its value changes.
+    pub track_id: Option<CsvColumn>,
+    pub delimiter: u8,
+    /// Whether the first row is a header. Columns can only be given by name if it is.
+    pub has_headers: bool,
+    pub coordinates: CoordinateFormat,
+    /// The datum of the coordinates.
+    pub datum: Datum,
+    /// Where tracks are split instead of drawn.
+    pub gaps: GapOptions,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            lat: CsvColumn::Name("latitude".to_string()),
+            lng: CsvColumn::Name("longitude".to_string()),
+            time: None,
+            time_format: None,
+            track_id: None,
+            delimiter: b',',
+            has_headers: true,
+            coordinates: CoordinateFormat::Degrees,
+            datum: Datum::Wgs84,
+            gaps: GapOptions::default(),
+            filter: FilterOptions::default(),
+        }
+    }
+}
+
+impl CsvOptions {
+    fn parse_coordinate(&self, value: &str) -> Option<f64> {
+        let value = value.trim();
+        match self.coordinates {
+            CoordinateFormat::Degrees => value.parse().ok(),
+            CoordinateFormat::E7 => value.parse::<i64>().ok().map(|value| value as f64 / 1e7),
+        }
+    }
+
+    fn parse_time(&self, value: &str) -> Option<i64> {
+        let value = value.trim();
+        match &self.time_format {
+            Some(format) => DateTime::parse_from_str(value, format)
+                .map(|time| time.timestamp_millis())
+                .or_else(|_| {
+                    NaiveDateTime::parse_from_str(value, format)
+                        .map(|time| time.and_utc().timestamp_millis())
+                })
+                .ok(),
+            None => parse_time(value).or_else(|| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .map(|seconds| (seconds * 1000.0).round() as i64)
+            }),
+        }
+    }
+}
+
+impl FogMap {
+    /// Adds the tracks of a CSV file, one point per row.
+    pub fn add_csv<R: Read>(
+        &mut self,
+        reader: R,
+        options: &CsvOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(options.delimiter)
+            .has_headers(options.has_headers)
+            .flexible(true)
+            .from_reader(reader);
+        let headers = if options.has_headers {
+            Some(
+                reader
+                    .headers()
+                    .map_err(|e| format!("Failed to read CSV: {}", e))?
+                    .clone(),
+            )
+        } else {
+            None
+        };
+        let resolve = |column: &Option<CsvColumn>| {
+            column
+                .as_ref()
+                .map(|column| column.resolve(headers.as_ref()))
+                .transpose()
+        };
+        let lat = options.lat.resolve(headers.as_ref())?;
+        let lng = options.lng.resolve(headers.as_ref())?;
+        let time = resolve(&options.time)?;
+        let track_id = resolve(&options.track_id)?;
+
+        let mut writer = TrackWriter::new(self, options.gaps)
+            .with_filter(options.filter)
+            .with_datum(options.datum);
+        let mut last_track_id = None;
+        let mut record = csv::StringRecord::new();
+        loop {
+            match reader.read_record(&mut record) {
+                Ok(true) => {}
+                Ok(false) => break,
+                // the reader can go on after an invalid row, but not after an I/O error
+                Err(e) if e.is_io_error() => return Err(format!("Failed to read CSV: {}", e)),
+                Err(e) => {
+                    writer.report_error(e.to_string());
+                    writer.drop_point();
+                    continue;
+                }
+            }
+            let line = record.position().map_or(0, |position| position.line());
+
+            if let Some(track_id) = track_id {
+                let id = record.get(track_id).unwrap_or_default();
+                if last_track_id.as_deref() != Some(id) {
+                    writer.end_segment();
+                    last_track_id = Some(id.to_string());
+                }
+            }
+
+            let coordinate = |index| record.get(index).and_then(|v| options.parse_coordinate(v));
+            let mut point = match (coordinate(lng), coordinate(lat)) {
+                (Some(lng), Some(lat)) => TrackPoint::new(lng, lat),
+                _ => {
+                    writer.report_error(format!("line {}: invalid coordinates", line));
+                    writer.drop_point();
+                    continue;
+                }
+            };
+            if let Some(time) = time {
+                let value = record.get(time).unwrap_or_default();
+                point.time = options.parse_time(value);
+                if point.time.is_none() && !value.trim().is_empty() {
+                    writer.report_error(format!("line {}: invalid time '{}'", line, value));
+                }
+            }
+            if point.is_valid() {
+                writer.push(point);
+            } else {
+                writer.drop_point();
+            }
+        }
+        Ok(writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_csv() {
+        let data = "\
+Track;Time;Lat;Lon
+a;01/05/2024 08:00:00;312000000;1215000000
+a;01/05/2024 08:00:10;312010000;1215010000
+b;01/05/2024 09:00:00;312100000;1215100000
+b;01/05/2024 09:00:10;x;1215110000
+b;01/05/2024 09:00:20;312120000;1215120000
+b;yesterday;999000000;1215130000
+";
+        let options = CsvOptions {
+            lat: CsvColumn::Name("lat".to_string()),
+            lng: CsvColumn::Index(3),
+            time: Some(CsvColumn::Name("time".to_string())),
+            time_format: Some("%d/%m/%Y %H:%M:%S".to_string()),
+            track_id: Some(CsvColumn::Name("track".to_string())),
+            delimiter: b';',
+            coordinates: CoordinateFormat::E7,
+            ..Default::default()
+        };
+        let mut fogmap = FogMap::new();
+        let summary = fogmap.add_csv(data.as_bytes(), &options).unwrap();
+        // every row is counted
+        assert_eq!(summary.points, 6);
+        assert_eq!(summary.dropped_points, 2);
+        assert_eq!(summary.segments, 2);
+        assert_eq!(summary.skipped_jumps, 0);
+        assert!(!summary.delta.is_empty());
+        assert_eq!(
+            summary.errors,
+            vec![
+                "line 5: invalid coordinates".to_string(),
+                "line 7: invalid time 'yesterday'".to_string()
+            ]
+        );
+
+        let options = CsvOptions {
+            time: Some(CsvColumn::Name("time".to_string())),
+            ..Default::default()
+        };
+        assert!(fogmap.add_csv(data.as_bytes(), &options).is_err());
+    }
+
+    #[test]
+    fn test_time() {
+        let options = CsvOptions::default();
+        assert_eq!(options.parse_time("1714550400"), Some(1714550400000));
+        assert_eq!(
+            options.parse_time("2024-05-01T08:00:00Z"),
+            Some(1714550400000)
+        );
+        let options = CsvOptions {
+            time_format: Some("%Y-%m-%d %H:%M:%S %z".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.parse_time("2024-05-01 16:00:00 +0800"),
+            Some(1714550400000)
+        );
+    }
+}
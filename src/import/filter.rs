@@ -0,0 +1,310 @@
+//! Filtering of GPS noise before a track is drawn.
+//!
+//! The stages run in this order, each only when configured:
+//! 1. points less accurate than `max_accuracy` are dropped,
+//! 2. spikes, a point far away from both of its neighbours that are themselves close, are
+//!    dropped,
+//! 3. the track is split where it moves faster than `max_speed`,
+//! 4. clusters of points drifting around a stationary location are collapsed into their centre,
+//! 5. the track is simplified with the Douglas-Peucker algorithm.
+
+use super::TrackPoint;
+use crate::utils::haversine_distance;
+use std::time::Duration;
+
+/// Removes A→B→A spikes: B is dropped when it is at least `min_distance` meters away from A and
+/// from C, while A and C are closer than that and at most `max_duration` apart.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpikeOptions {
+    pub max_duration: Duration,
+    pub min_distance: f64,
+}
+
+/// Collapses the consecutive points staying within `radius` meters of the first one for at least
+/// `min_duration` into a single point.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StationaryOptions {
+    pub radius: f64,
+    pub min_duration: Duration,
+}
+
+/// GPS noise removed from the tracks before they are drawn, taken by the importers as their
+/// `filter` option. The stages are run by [`filter_track`] and are all disabled by default,
+/// [`FilterOptions::recommended`] suits phone tracks.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FilterOptions {
+    /// In meters per second. Points without a time are never split on speed.
+    pub max_speed: Option<f64>,
+    /// In meters. Points without an accuracy are kept.
+    pub max_accuracy: Option<f64>,
+    pub spikes: Option<SpikeOptions>,
+    pub stationary: Option<StationaryOptions>,
+    /// Tolerance in meters of the Douglas-Peucker simplification.
+    pub simplify_tolerance: Option<f64>,
+}
+
+impl FilterOptions {
+    /// Values suited to phone tracks, for walking up to driving on a highway.
+    pub fn recommended() -> Self {
+        Self {
+            max_speed: Some(70.0),
+            max_accuracy: Some(100.0),
+            spikes: Some(SpikeOptions {
+                max_duration: Duration::from_secs(60),
+                min_distance: 200.0,
+            }),
+            stationary: Some(StationaryOptions {
+                radius: 50.0,
+                min_duration: Duration::from_secs(5 * 60),
+            }),
+            simplify_tolerance: Some(2.0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+fn distance(a: &TrackPoint, b: &TrackPoint) -> f64 {
+    haversine_distance(a.lng, a.lat, b.lng, b.lat)
+}
+
+/// Filters a track, returning the segments it is split into.
+pub fn filter_track(points: &[TrackPoint], options: &FilterOptions) -> Vec<Vec<TrackPoint>> {
+    let mut points: Vec<TrackPoint> = match options.max_accuracy {
+        Some(max_accuracy) => points
+            .iter()
+            .filter(|point| {
+                point
+                    .accuracy
+                    .is_none_or(|accuracy| accuracy <= max_accuracy)
+            })
+            .copied()
+            .collect(),
+        None => points.to_vec(),
+    };
+    if let Some(spikes) = options.spikes {
+        points = remove_spikes(&points, &spikes);
+    }
+    let segments = match options.max_speed {
+        Some(max_speed) => split_on_speed(points, max_speed),
+        None => vec![points],
+    };
+    segments
+        .into_iter()
+        .map(|segment| {
+            let segment = match options.stationary {
+                Some(stationary) => collapse_stationary(&segment, &stationary),
+                None => segment,
+            };
+            match options.simplify_tolerance {
+                Some(tolerance) => simplify(&segment, tolerance),
+                None => segment,
+            }
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+fn remove_spikes(points: &[TrackPoint], options: &SpikeOptions) -> Vec<TrackPoint> {
+    let mut kept: Vec<TrackPoint> = Vec::with_capacity(points.len());
+    for (i, point) in points.iter().enumerate() {
+        let is_spike = match (kept.last(), points.get(i + 1)) {
+            (Some(before), Some(after)) => {
+                let quick = match (before.time, after.time) {
+                    (Some(start), Some(end)) => {
+                        (end - start).unsigned_abs() as u128 <= options.max_duration.as_millis()
+                    }
+                    _ => false,
+                };
+                quick
+                    && distance(before, point) >= options.min_distance
+                    && distance(point, after) >= options.min_distance
+                    && distance(before, after) < options.min_distance
+            }
+            _ => false,
+        };
+        if !is_spike {
+            kept.push(*point);
+        }
+    }
+    kept
+}
+
+fn split_on_speed(points: Vec<TrackPoint>, max_speed: f64) -> Vec<Vec<TrackPoint>> {
+    let mut segments = Vec::new();
+    let mut segment: Vec<TrackPoint> = Vec::new();
+    for point in points {
+        if let Some(last) = segment.last() {
+            if let (Some(start), Some(end)) = (last.time, point.time) {
+                // points recorded at the same time are taken as one second apart
+                let seconds = ((end - start).abs() as f64 / 1000.0).max(1.0);
+                if distance(last, &point) / seconds > max_speed {
+                    segments.push(std::mem::take(&mut segment));
+                }
+            }
+        }
+        segment.push(point);
+    }
+    segments.push(segment);
+    segments
+}
+
+fn collapse_stationary(points: &[TrackPoint], options: &StationaryOptions) -> Vec<TrackPoint> {
+    let mut collapsed = Vec::with_capacity(points.len());
+    let mut start = 0;
+    while start < points.len() {
+        let anchor = &points[start];
+        let end = start
+            + points[start..]
+                .iter()
+                .take_while(|point| distance(anchor, point) <= options.radius)
+                .count();
+        let cluster = &points[start..end];
+        let duration = match (anchor.time, cluster[cluster.len() - 1].time) {
+            (Some(first), Some(last)) => (last - first).unsigned_abs() as u128,
+            _ => 0,
+        };
+        if cluster.len() > 1 && duration >= options.min_duration.as_millis() {
+            let count = cluster.len() as f64;
+            collapsed.push(TrackPoint {
+                lng: cluster.iter().map(|point| point.lng).sum::<f64>() / count,
+                lat: cluster.iter().map(|point| point.lat).sum::<f64>() / count,
+                ..*anchor
+            });
+            start = end;
+        } else {
+            collapsed.push(*anchor);
+            start += 1;
+        }
+    }
+    collapsed
+}
+
+// Douglas-Peucker, measuring distances on a local equirectangular projection.
+fn simplify(points: &[TrackPoint], tolerance: f64) -> Vec<TrackPoint> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    const METERS_PER_DEGREE: f64 = 6_371_008.8 * std::f64::consts::PI / 180.0;
+    let scale = points[0].lat.to_radians().cos();
+    let project = |point: &TrackPoint| {
+        (
+            point.lng * scale * METERS_PER_DEGREE,
+            point.lat * METERS_PER_DEGREE,
+        )
+    };
+    let projected: Vec<(f64, f64)> = points.iter().map(project).collect();
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let (ax, ay) = projected[first];
+        let (bx, by) = projected[last];
+        let (dx, dy) = (bx - ax, by - ay);
+        let length = (dx * dx + dy * dy).sqrt();
+        let (index, max_distance) = (first + 1..last)
+            .map(|i| {
+                let (px, py) = projected[i];
+                let distance = if length == 0.0 {
+                    ((px - ax).powi(2) + (py - ay).powi(2)).sqrt()
+                } else {
+                    (dy * (px - ax) - dx * (py - ay)).abs() / length
+                };
+                (i, distance)
+            })
+            .fold(
+                (first, 0.0),
+                |max, item| if item.1 > max.1 { item } else { max },
+            );
+        if max_distance > tolerance {
+            keep[index] = true;
+            ranges.push((first, index));
+            ranges.push((index, last));
+        }
+    }
+    points
+        .iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| keep.then_some(*point))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a point every 10 seconds, about 11 meters apart, heading north
+    fn walk(count: usize) -> Vec<TrackPoint> {
+        (0..count)
+            .map(|i| TrackPoint::with_time(121.5, 31.2 + i as f64 * 1e-4, i as i64 * 10_000))
+            .collect()
+    }
+
+    #[test]
+    fn test_spikes_and_speed() {
+        let mut points = walk(5);
+        points[2].lng += 0.05;
+        points[4] = TrackPoint::with_time(122.5, 31.2, 40_000);
+        let options = FilterOptions {
+            max_speed: Some(50.0),
+            spikes: Some(SpikeOptions {
+                max_duration: Duration::from_secs(30),
+                min_distance: 200.0,
+            }),
+            ..Default::default()
+        };
+        let segments = filter_track(&points, &options);
+        assert_eq!(
+            segments,
+            vec![vec![points[0], points[1], points[3]], vec![points[4]]]
+        );
+    }
+
+    #[test]
+    fn test_accuracy_and_stationary() {
+        let mut points = walk(3);
+        for i in 0..10 {
+            let mut point =
+                TrackPoint::with_time(121.5 + (i % 2) as f64 * 1e-4, 31.2006, 30_000 + i * 60_000);
+            point.accuracy = Some(20.0);
+            points.push(point);
+        }
+        points[1].accuracy = Some(500.0);
+        let options = FilterOptions {
+            max_accuracy: Some(100.0),
+            stationary: Some(StationaryOptions {
+                radius: 30.0,
+                min_duration: Duration::from_secs(5 * 60),
+            }),
+            ..Default::default()
+        };
+        let segments = filter_track(&points, &options);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].len(), 3);
+        assert!((segments[0][2].lng - 121.50005).abs() < 1e-9);
+        assert_eq!(segments[0][2].time, Some(30_000));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let mut points = walk(20);
+        points[10].lng += 1e-3;
+        let options = FilterOptions {
+            simplify_tolerance: Some(1.0),
+            ..Default::default()
+        };
+        let segments = filter_track(&points, &options);
+        assert_eq!(
+            segments,
+            vec![vec![
+                points[0], points[9], points[10], points[11], points[19]
+            ]]
+        );
+        assert!(options.is_enabled());
+        assert!(!FilterOptions::default().is_enabled());
+    }
+}
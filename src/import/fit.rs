@@ -0,0 +1,270 @@
+//! Import of Garmin FIT activity files.
+//!
+//! The positions are read from the `record` messages, in semicircles. A file may hold several
+//! sessions, as multisport activities do, and several FIT files may be chained one after the
+//! other. A truncated or corrupt file is reported in the summary, the records read before the
+//! damage are still drawn.
+
+use super::filter::FilterOptions;
+use super::{GapOptions, ImportSummary, TrackPoint, TrackWriter};
+use crate::datum::Datum;
+use crate::FogMap;
+use std::io::Read;
+
+#[derive(Debug, Clone, Default)]
+pub struct FitOptions {
+    /// Where the activity is split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the file.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+// 1989-12-31T00:00:00Z, the origin of FIT timestamps, in Unix seconds
+const FIT_EPOCH: i64 = 631_065_600;
+const SEMICIRCLES_TO_DEGREES: f64 = 180.0 / (1u64 << 31) as f64;
+
+// global message numbers
+const SESSION: u16 = 18;
+const RECORD: u16 = 20;
+// field numbers
+const POSITION_LAT: u8 = 0;
+const POSITION_LONG: u8 = 1;
+const TIMESTAMP: u8 = 253;
+
+const CRC_TABLE: [u16; 16] = [
+    0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800, 0xB401,
+    0x5000, 0x9C01, 0x8801, 0x4400,
+];
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        for nibble in [byte & 0xF, byte >> 4] {
+            let tmp = CRC_TABLE[(crc & 0xF) as usize];
+            crc = (crc >> 4) & 0x0FFF;
+            crc = crc ^ tmp ^ CRC_TABLE[nibble as usize];
+        }
+        crc
+    })
+}
+
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+}
+
+struct MessageDefinition {
+    big_endian: bool,
+    global: u16,
+    fields: Vec<FieldDefinition>,
+    // total size of the developer fields, which are skipped
+    developer_size: usize,
+}
+
+struct Bytes<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or_else(|| format!("Message truncated at byte {}", self.position))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+// Returns the header size and the size of the records.
+fn read_header(file: &[u8]) -> Result<(usize, usize), String> {
+    let header_size = *file.first().ok_or("Empty FIT file")? as usize;
+    if !(header_size == 12 || header_size == 14) || file.len() < header_size {
+        return Err("Not a FIT file".to_string());
+    }
+    if &file[8..12] != b".FIT" {
+        return Err("Not a FIT file".to_string());
+    }
+    if header_size == 14 {
+        // a zero CRC means the header has none
+        let crc = u16::from_le_bytes([file[12], file[13]]);
+        if crc != 0 && crc != crc16(&file[..12]) {
+            return Err("FIT header CRC mismatch".to_string());
+        }
+    }
+    let data_size = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+    Ok((header_size, data_size))
+}
+
+fn read_records(data: &[u8], writer: &mut TrackWriter) -> Result<(), String> {
+    let mut bytes = Bytes { data, position: 0 };
+    let mut definitions: [Option<MessageDefinition>; 16] = Default::default();
+    let mut last_timestamp: Option<u32> = None;
+
+    while bytes.position < data.len() {
+        let header = bytes.byte()?;
+        let (local, mut timestamp) = if header & 0x80 != 0 {
+            // a compressed timestamp header holds the 5 low bits of the timestamp
+            let offset = (header & 0x1F) as u32;
+            let timestamp = last_timestamp.map(|last| {
+                let timestamp = (last & !0x1F) | offset;
+                if offset < last & 0x1F {
+                    timestamp + 0x20
+                } else {
+                    timestamp
+                }
+            });
+            (((header >> 5) & 0x3) as usize, timestamp)
+        } else if header & 0x40 != 0 {
+            let developer = header & 0x20 != 0;
+            bytes.take(1)?;
+            let big_endian = bytes.byte()? == 1;
+            let global = bytes.take(2)?;
+            let global = if big_endian {
+                u16::from_be_bytes([global[0], global[1]])
+            } else {
+                u16::from_le_bytes([global[0], global[1]])
+            };
+            let field_count = bytes.byte()?;
+            let mut fields = Vec::with_capacity(field_count as usize);
+            for _ in 0..field_count {
+                let field = bytes.take(3)?;
+                fields.push(FieldDefinition {
+                    number: field[0],
+                    size: field[1] as usize,
+                });
+            }
+            let mut developer_size = 0;
+            if developer {
+                for _ in 0..bytes.byte()? {
+                    developer_size += bytes.take(3)?[1] as usize;
+                }
+            }
+            definitions[(header & 0xF) as usize] = Some(MessageDefinition {
+                big_endian,
+                global,
+                fields,
+                developer_size,
+            });
+            continue;
+        } else {
+            ((header & 0xF) as usize, None)
+        };
+
+        let definition = definitions[local].as_ref().ok_or_else(|| {
+            format!(
+                "Message of undefined local type {} at byte {}",
+                local,
+                bytes.position - 1
+            )
+        })?;
+        let mut lat = None;
+        let mut lng = None;
+        for field in &definition.fields {
+            let value = bytes.take(field.size)?;
+            if field.size != 4 {
+                continue;
+            }
+            let value = [value[0], value[1], value[2], value[3]];
+            let value = if definition.big_endian {
+                u32::from_be_bytes(value)
+            } else {
+                u32::from_le_bytes(value)
+            };
+            match field.number {
+                TIMESTAMP if value != u32::MAX => timestamp = Some(value),
+                POSITION_LAT if value != i32::MAX as u32 => lat = Some(value as i32),
+                POSITION_LONG if value != i32::MAX as u32 => lng = Some(value as i32),
+                _ => {}
+            }
+        }
+        bytes.take(definition.developer_size)?;
+        if timestamp.is_some() {
+            last_timestamp = timestamp;
+        }
+
+        match definition.global {
+            RECORD => match (lat, lng) {
+                (Some(lat), Some(lng)) => {
+                    let mut point = TrackPoint::new(
+                        lng as f64 * SEMICIRCLES_TO_DEGREES,
+                        lat as f64 * SEMICIRCLES_TO_DEGREES,
+                    );
+                    point.time = timestamp.map(|timestamp| (FIT_EPOCH + timestamp as i64) * 1000);
+                    writer.push(point);
+                }
+                // records without a fix, for instance on a home trainer
+                _ => writer.drop_point(),
+            },
+            // the legs of a multisport activity are not joined
+            SESSION => writer.end_segment(),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl FogMap {
+    /// Adds the records of a FIT activity file.
+    pub fn add_fit<R: Read>(
+        &mut self,
+        mut reader: R,
+        options: &FitOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read FIT file: {}", e))?;
+
+        let mut writer = TrackWriter::new(self, options.gaps)
+            .with_filter(options.filter)
+            .with_datum(options.datum);
+        let mut offset = 0;
+        // chained FIT files follow each other
+        while offset < data.len() {
+            let file = &data[offset..];
+            let (header_size, data_size) = match read_header(file) {
+                Ok(sizes) => sizes,
+                Err(e) if offset == 0 => return Err(e),
+                Err(e) => {
+                    writer.report_error(format!("byte {}: {}", offset, e));
+                    break;
+                }
+            };
+            // the data size is read from the file, it may overflow on 32 bit targets
+            let Some(end) = header_size
+                .checked_add(data_size)
+                .filter(|end| end.checked_add(2).is_some())
+            else {
+                writer.report_error(format!(
+                    "byte {}: FIT file corrupt, invalid data size",
+                    offset
+                ));
+                break;
+            };
+            let result = read_records(&file[header_size..end.min(file.len())], &mut writer);
+            if file.len() < end + 2 {
+                writer.report_error(format!(
+                    "FIT file truncated, {} of {} bytes",
+                    file.len(),
+                    end + 2
+                ));
+                break;
+            }
+            if let Err(e) = result {
+                writer.report_error(e);
+            }
+            if u16::from_le_bytes([file[end], file[end + 1]]) != crc16(&file[..end]) {
+                writer.report_error("FIT file CRC mismatch".to_string());
+            }
+            offset += end + 2;
+        }
+        Ok(writer.finish())
+    }
+}
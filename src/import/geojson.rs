@@ -0,0 +1,324 @@
+//! Import of GeoJSON, either a `FeatureCollection`, a single `Feature` or a bare geometry.
+//!
+//! Line strings are drawn as tracks, points are stamped as circles and polygons are filled.
+
+use super::filter::FilterOptions;
+use super::{GapOptions, ImportSummary, TrackPoint};
+use crate::datum::Datum;
+use crate::FogMap;
+use serde_json::{Map, Value};
+use std::io::{BufReader, Read};
+
+/// Only imports the features whose property `key` has one of the given `values`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyFilter {
+    pub key: String,
+    pub values: Vec<Value>,
+}
+
+impl PropertyFilter {
+    pub fn matches(&self, properties: Option<&Map<String, Value>>) -> bool {
+        properties
+            .and_then(|properties| properties.get(&self.key))
+            .is_some_and(|value| self.values.contains(value))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeoJsonOptions {
+    /// Where line strings are split instead of drawn. GeoJSON has no time, so by default they
+    /// are never split.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates, RFC 7946 requires WGS-84 but exports from Chinese maps
+    /// are not.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+    /// Radius in meters of the area visited around a point.
+    pub point_radius: f64,
+    /// When set, only the features matching the filter are imported. Bare geometries always are.
+    pub property_filter: Option<PropertyFilter>,
+}
+
+impl Default for GeoJsonOptions {
+    fn default() -> Self {
+        Self {
+            gaps: GapOptions::none(),
+            datum: Datum::Wgs84,
+            filter: FilterOptions::default(),
+            point_radius: 25.0,
+            property_filter: None,
+        }
+    }
+}
+
+impl FogMap {
+    /// Adds the geometries of a GeoJSON document.
+    pub fn add_geojson<R: Read>(
+        &mut self,
+        reader: R,
+        options: &GeoJsonOptions,
+    ) -> Result<ImportSummary, String> {
+        let value: Value = serde_json::from_reader(BufReader::new(reader))
+            .map_err(|e| format!("Failed to read GeoJSON: {}", e))?;
+        self.add_geojson_value(&value, options)
+    }
+
+    /// Adds the geometries of an already parsed GeoJSON document.
+    pub fn add_geojson_value(
+        &mut self,
+        value: &Value,
+        options: &GeoJsonOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut summary = ImportSummary::default();
+        self.add_geojson_object(value, options, &mut summary)?;
+        Ok(summary)
+    }
+
+    fn add_geojson_object(
+        &mut self,
+        value: &Value,
+        options: &GeoJsonOptions,
+        summary: &mut ImportSummary,
+    ) -> Result<(), String> {
+        let object_type = value["type"]
+            .as_str()
+            .ok_or("GeoJSON object without a type")?;
+        let coordinates = &value["coordinates"];
+        match object_type {
+            "FeatureCollection" => {
+                for feature in array(&value["features"])? {
+                    self.add_geojson_object(feature, options, summary)?;
+                }
+            }
+            "Feature" => {
+                let matches = options
+                    .property_filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.matches(value["properties"].as_object()));
+                // a feature may have no geometry
+                if matches && !value["geometry"].is_null() {
+                    self.add_geojson_object(&value["geometry"], options, summary)?;
+                }
+            }
+            "GeometryCollection" => {
+                for geometry in array(&value["geometries"])? {
+                    self.add_geojson_object(geometry, options, summary)?;
+                }
+            }
+            "Point" => {
+                self.add_geojson_point(position(coordinates, options.datum)?, options, summary)
+            }
+            "MultiPoint" => {
+                for point in array(coordinates)? {
+                    self.add_geojson_point(position(point, options.datum)?, options, summary);
+                }
+            }
+            "LineString" => {
+                self.add_track(
+                    &line_string(coordinates, options.datum, summary)?,
+                    &options.gaps,
+                    &options.filter,
+                    summary,
+                );
+            }
+            "MultiLineString" => {
+                for line in array(coordinates)? {
+                    self.add_track(
+                        &line_string(line, options.datum, summary)?,
+                        &options.gaps,
+                        &options.filter,
+                        summary,
+                    );
+                }
+            }
+            "Polygon" => self.add_geojson_polygon(coordinates, options, summary)?,
+            "MultiPolygon" => {
+                for polygon in array(coordinates)? {
+                    self.add_geojson_polygon(polygon, options, summary)?;
+                }
+            }
+            _ => return Err(format!("Unknown GeoJSON type: {}", object_type)),
+        }
+        Ok(())
+    }
+
+    fn add_geojson_point(
+        &mut self,
+        point: Option<TrackPoint>,
+        options: &GeoJsonOptions,
+        summary: &mut ImportSummary,
+    ) {
+        summary.points += 1;
+        let Some(point) = point else {
+            summary.dropped_points += 1;
+            return;
+        };
+        summary
+            .delta
+            .merge(self.add_circle(point.lng, point.lat, options.point_radius));
+    }
+
+    fn add_geojson_polygon(
+        &mut self,
+        coordinates: &Value,
+        options: &GeoJsonOptions,
+        summary: &mut ImportSummary,
+    ) -> Result<(), String> {
+        let rings = array(coordinates)?
+            .iter()
+            .map(|ring| {
+                Ok(line_string(ring, options.datum, summary)?
+                    .into_iter()
+                    .map(|point| (point.lng, point.lat))
+                    .collect())
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        summary.points += rings.iter().map(Vec::len).sum::<usize>();
+        summary.delta.merge(self.add_polygon(&rings));
+        Ok(())
+    }
+}
+
+fn array(value: &Value) -> Result<&Vec<Value>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("Expected an array in GeoJSON, found: {}", value))
+}
+
+// A position, `None` when its coordinates are out of range.
+fn position(value: &Value, datum: Datum) -> Result<Option<TrackPoint>, String> {
+    let position = array(value)?;
+    match (
+        position.first().and_then(Value::as_f64),
+        position.get(1).and_then(Value::as_f64),
+    ) {
+        (Some(lng), Some(lat)) => Ok(Some(TrackPoint::new(lng, lat))
+            .filter(TrackPoint::is_valid)
+            .map(|point| point.to_wgs84(datum))),
+        _ => Err(format!("Invalid GeoJSON position: {}", value)),
+    }
+}
+
+// The valid positions of a line string, the others are counted as dropped.
+fn line_string(
+    value: &Value,
+    datum: Datum,
+    summary: &mut ImportSummary,
+) -> Result<Vec<TrackPoint>, String> {
+    let positions = array(value)?;
+    let mut points = Vec::with_capacity(positions.len());
+    for value in positions {
+        match position(value, datum)? {
+            Some(point) => points.push(point),
+            None => {
+                summary.points += 1;
+                summary.dropped_points += 1;
+            }
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn collection() -> Value {
+        json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": {"kind": "recorded"},
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [[121.50, 31.20], [121.51, 31.21, 5.0], [121.52, 31.20]]
+                    }
+                },
+                {
+                    "type": "Feature",
+                    "properties": {"kind": "planned"},
+                    "geometry": {
+                        "type": "MultiLineString",
+                        "coordinates": [[[121.60, 31.20], [121.61, 31.21]], [[121.70, 31.20], [121.71, 31.21]]]
+                    }
+                },
+                {
+                    "type": "Feature",
+                    "properties": {"kind": "recorded"},
+                    "geometry": {"type": "Point", "coordinates": [121.80, 31.20]}
+                },
+                {
+                    "type": "Feature",
+                    "properties": null,
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[121.90, 31.20], [121.901, 31.20], [121.901, 31.201], [121.90, 31.20]]]
+                    }
+                },
+                {"type": "Feature", "properties": {}, "geometry": null}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_add_geojson() {
+        let mut fogmap = FogMap::new();
+        let summary = fogmap
+            .add_geojson(
+                collection().to_string().as_bytes(),
+                &GeoJsonOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(summary.points, 3 + 4 + 1 + 4);
+        assert_eq!(summary.segments, 3);
+        assert_eq!(summary.skipped_jumps, 0);
+        assert!(!summary.delta.is_empty());
+
+        let options = GeoJsonOptions {
+            property_filter: Some(PropertyFilter {
+                key: "kind".to_string(),
+                values: vec![json!("recorded")],
+            }),
+            point_radius: 0.0,
+            ..Default::default()
+        };
+        let summary = FogMap::new()
+            .add_geojson_value(&collection(), &options)
+            .unwrap();
+        assert_eq!(summary.points, 3 + 1);
+        assert_eq!(summary.segments, 1);
+
+        // a bare geometry
+        let summary = FogMap::new()
+            .add_geojson_value(
+                &json!({"type": "Point", "coordinates": [121.80, 31.20]}),
+                &options,
+            )
+            .unwrap();
+        assert_eq!(summary.delta.new_pixels, 1);
+
+        // positions out of range are dropped
+        let summary = FogMap::new()
+            .add_geojson_value(
+                &json!({"type": "MultiPoint", "coordinates": [[121.80, 31.20], [-190.0, 31.20]]}),
+                &options,
+            )
+            .unwrap();
+        assert_eq!((summary.points, summary.dropped_points), (2, 1));
+        let summary = FogMap::new()
+            .add_geojson_value(
+                &json!({"type": "LineString", "coordinates": [[121.80, 31.20], [121.81, 95.0], [121.81, 31.21]]}),
+                &options,
+            )
+            .unwrap();
+        assert_eq!((summary.points, summary.dropped_points), (3, 1));
+
+        assert!(FogMap::new()
+            .add_geojson_value(&json!({"type": "Point", "coordinates": [121.8]}), &options)
+            .is_err());
+        assert!(FogMap::new().add_geojson("{".as_bytes(), &options).is_err());
+    }
+}
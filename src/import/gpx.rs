@@ -0,0 +1,241 @@
+//! Import of GPX 1.1 files.
+//!
+//! Track segments (`trk/trkseg/trkpt`) are always imported, routes (`rte/rtept`) and waypoints
+//! (`wpt`) only when asked for.
+
+use super::filter::FilterOptions;
+use super::{parse_time, GapOptions, ImportSummary, TrackPoint};
+use crate::datum::Datum;
+use crate::FogMap;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::{BufReader, Read};
+
+#[derive(Debug, Clone, Default)]
+pub struct GpxOptions {
+    /// Where tracks are split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the file.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+    /// Also import routes, which are usually planned rather than recorded.
+    pub include_routes: bool,
+    /// Also import waypoints, each as a single point.
+    pub include_waypoints: bool,
+}
+
+/// Reads the segments of a GPX file, waypoints become segments of a single point. The points
+/// without valid coordinates are left out and counted as dropped in `summary`.
+pub fn read_gpx<R: Read>(
+    reader: R,
+    options: &GpxOptions,
+    summary: &mut ImportSummary,
+) -> Result<Vec<Vec<TrackPoint>>, String> {
+    let mut reader = Reader::from_reader(BufReader::new(reader));
+    reader.config_mut().trim_text(true);
+
+    let mut segments = Vec::new();
+    // the segment being read, either a `trkseg` or a `rte`
+    let mut segment: Option<Vec<TrackPoint>> = None;
+    // the point being read, `None` when its coordinates are invalid, and the element it comes from
+    let mut point: Option<(Option<TrackPoint>, Vec<u8>)> = None;
+    let mut in_time = false;
+
+    let mut buf = Vec::new();
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("Failed to read GPX: {}", e))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                match e.local_name().as_ref() {
+                    b"trkseg" if !is_empty => segment = Some(Vec::new()),
+                    b"rte" if !is_empty && options.include_routes => segment = Some(Vec::new()),
+                    name @ (b"trkpt" | b"rtept" | b"wpt") => {
+                        point = Some((parse_point(e)?, name.to_vec()));
+                        if is_empty {
+                            let point = point.take();
+                            finish_point(point, &mut segment, &mut segments, options, summary);
+                        }
+                    }
+                    b"time" if point.is_some() && !is_empty => in_time = true,
+                    _ => {}
+                }
+            }
+            Event::Text(e) if in_time => {
+                let text = e
+                    .decode()
+                    .map_err(|e| format!("Failed to read GPX: {}", e))?;
+                if let Some((Some(point), _)) = point.as_mut() {
+                    point.time = parse_time(&text);
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"time" => in_time = false,
+                b"trkpt" | b"rtept" | b"wpt" => {
+                    let point = point.take();
+                    finish_point(point, &mut segment, &mut segments, options, summary)
+                }
+                b"trkseg" | b"rte" => {
+                    if let Some(segment) = segment.take() {
+                        segments.push(segment);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(segments)
+}
+
+// The point of an element, `None` when its coordinates are missing or invalid.
+fn parse_point(e: &BytesStart) -> Result<Option<TrackPoint>, String> {
+    let coordinate = |name: &str| -> Result<Option<f64>, String> {
+        let value = e
+            .try_get_attribute(name)
+            .map_err(|e| format!("Failed to read GPX: {}", e))?;
+        Ok(value.and_then(|value| {
+            std::str::from_utf8(&value.value)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+        }))
+    };
+    Ok(match (coordinate("lon")?, coordinate("lat")?) {
+        (Some(lng), Some(lat)) => Some(TrackPoint::new(lng, lat)).filter(TrackPoint::is_valid),
+        _ => None,
+    })
+}
+
+fn finish_point(
+    point: Option<(Option<TrackPoint>, Vec<u8>)>,
+    segment: &mut Option<Vec<TrackPoint>>,
+    segments: &mut Vec<Vec<TrackPoint>>,
+    options: &GpxOptions,
+    summary: &mut ImportSummary,
+) {
+    let Some((point, name)) = point else {
+        return;
+    };
+    let target = if name == b"wpt" {
+        options.include_waypoints.then_some(None)
+    } else {
+        segment.as_mut().map(Some)
+    };
+    let Some(target) = target else {
+        return;
+    };
+    match (point, target) {
+        (Some(point), Some(segment)) => segment.push(point),
+        (Some(point), None) => segments.push(vec![point]),
+        (None, _) => {
+            summary.points += 1;
+            summary.dropped_points += 1;
+        }
+    }
+}
+
+impl FogMap {
+    /// Adds the tracks of a GPX file.
+    pub fn add_gpx<R: Read>(
+        &mut self,
+        reader: R,
+        options: &GpxOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut summary = ImportSummary::default();
+        for segment in read_gpx(reader, options, &mut summary)? {
+            let segment: Vec<_> = segment
+                .into_iter()
+                .map(|point| point.to_wgs84(options.datum))
+                .collect();
+            self.add_track(&segment, &options.gaps, &options.filter, &mut summary);
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <wpt lat="31.2" lon="121.5"><name>Start</name></wpt>
+  <rte>
+    <rtept lat="31.2" lon="121.5"/>
+    <rtept lat="31.21" lon="121.52"/>
+  </rte>
+  <trk>
+    <name>Morning ride</name>
+    <trkseg>
+      <trkpt lat="31.2000" lon="121.5000"><ele>4.0</ele><time>2024-05-01T08:00:00Z</time></trkpt>
+      <trkpt lat="31.2010" lon="121.5010"><time>2024-05-01T08:00:10Z</time></trkpt>
+      <trkpt lat="31.2020" lon="121.5020"><time>2024-05-01T09:00:00Z</time></trkpt>
+      <trkpt lat="31.2030" lon="121.5030"><time>2024-05-01T09:00:10Z</time></trkpt>
+    </trkseg>
+    <trkseg>
+      <trkpt lat="31.3000" lon="121.6000"/>
+      <trkpt lat="31.3010" lon="121.6010"/>
+      <trkpt lat="32.3010" lon="121.6010"/>
+      <trkpt lat="not a number" lon="121.6010"/>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+    #[test]
+    fn test_read_gpx() {
+        let mut summary = ImportSummary::default();
+        let segments = read_gpx(GPX.as_bytes(), &GpxOptions::default(), &mut summary).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].len(), 4);
+        assert_eq!(segments[0][1].time, Some(1714550410000));
+        assert_eq!(segments[1].len(), 3);
+        assert_eq!(segments[1][0].time, None);
+        // the point with an invalid latitude
+        assert_eq!((summary.points, summary.dropped_points), (1, 1));
+
+        let options = GpxOptions {
+            include_routes: true,
+            include_waypoints: true,
+            ..Default::default()
+        };
+        let segments = read_gpx(GPX.as_bytes(), &options, &mut ImportSummary::default()).unwrap();
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0], vec![TrackPoint::new(121.5, 31.2)]);
+        assert_eq!(segments[1].len(), 2);
+    }
+
+    #[test]
+    fn test_add_gpx() {
+        let mut fogmap = FogMap::new();
+        let summary = fogmap
+            .add_gpx(GPX.as_bytes(), &GpxOptions::default())
+            .unwrap();
+        assert_eq!(summary.points, 8);
+        assert_eq!(summary.dropped_points, 1);
+        // the first segment is split by the hour long pause, the second by the 100km jump
+        assert_eq!(summary.skipped_jumps, 2);
+        assert_eq!(summary.segments, 4);
+        assert!(!summary.delta.is_empty());
+
+        let options = GpxOptions {
+            gaps: GapOptions {
+                max_time_gap: Some(Duration::from_secs(2 * 60 * 60)),
+                max_distance_gap: None,
+            },
+            ..Default::default()
+        };
+        let summary = FogMap::new().add_gpx(GPX.as_bytes(), &options).unwrap();
+        assert_eq!(summary.skipped_jumps, 0);
+        assert_eq!(summary.segments, 2);
+
+        assert!(FogMap::new()
+            .add_gpx("<gpx><trk><trkseg></trk>".as_bytes(), &options)
+            .is_err());
+    }
+}
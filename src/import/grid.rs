@@ -0,0 +1,98 @@
+//! Import of the cells of discrete global grids, H3 and geohash, as visited areas.
+//!
+//! The pixels of the map whose centre is within a cell are marked as visited, as well as the
+//! pixel holding the centre of the cell, so that cells smaller than a pixel are kept.
+
+use crate::{ExplorationDelta, FogMap};
+use h3o::{CellIndex, LatLng};
+
+impl FogMap {
+    /// Marks the areas of H3 cells as visited.
+    pub fn add_h3_cells(&mut self, cells: &[CellIndex]) -> ExplorationDelta {
+        let mut delta = ExplorationDelta::new();
+        for &cell in cells {
+            let vertices: Vec<(f64, f64)> = cell
+                .boundary()
+                .iter()
+                .map(|vertex| (vertex.lng(), vertex.lat()))
+                .collect();
+            delta.merge(self.add_polygon(&[vertices]));
+            let center = LatLng::from(cell);
+            delta.merge(self.add_point(center.lng(), center.lat()));
+        }
+        delta
+    }
+
+    /// Marks the areas of geohashes as visited. No area is marked when a geohash is invalid.
+    pub fn add_geohashes(&mut self, geohashes: &[&str]) -> Result<ExplorationDelta, String> {
+        let rects = geohashes
+            .iter()
+            .map(|hash| {
+                geohash::decode_bbox(hash).map_err(|e| format!("Invalid geohash {}: {}", hash, e))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let mut delta = ExplorationDelta::new();
+        for rect in rects {
+            let (min, max) = (rect.min(), rect.max());
+            let vertices = vec![
+                (min.x, max.y),
+                (max.x, max.y),
+                (max.x, min.y),
+                (min.x, min.y),
+            ];
+            delta.merge(self.add_polygon(&[vertices]));
+            let center = rect.center();
+            delta.merge(self.add_point(center.x, center.y));
+        }
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h3o::Resolution;
+
+    #[test]
+    fn test_add_h3_cells() {
+        let cell = LatLng::new(31.20, 121.40)
+            .unwrap()
+            .to_cell(Resolution::Nine);
+        let mut fogmap = FogMap::new();
+        let delta = fogmap.add_h3_cells(&[cell]);
+        assert!(!delta.is_empty());
+        // the cell is fully covered, and nothing else
+        assert_eq!(fogmap.to_h3_cells(9, 0.0).unwrap(), vec![cell]);
+        assert_eq!(fogmap.to_h3_cells(9, 0.95).unwrap(), vec![cell]);
+
+        // a cell smaller than a pixel
+        let mut fogmap = FogMap::new();
+        let cell = LatLng::new(31.20, 121.40)
+            .unwrap()
+            .to_cell(Resolution::Fifteen);
+        fogmap.add_h3_cells(&[cell]);
+        assert_eq!(
+            fogmap.to_h3_cells(10, 0.0).unwrap(),
+            vec![cell.parent(Resolution::Ten).unwrap()]
+        );
+
+        // a cell across the antimeridian
+        let mut fogmap = FogMap::new();
+        let cell = LatLng::new(0.0, 180.0).unwrap().to_cell(Resolution::Eight);
+        fogmap.add_h3_cells(&[cell]);
+        assert_eq!(fogmap.to_h3_cells(8, 0.95).unwrap(), vec![cell]);
+    }
+
+    #[test]
+    fn test_add_geohashes() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_geohashes(&["wtw3s"]).unwrap();
+        assert_eq!(fogmap.to_geohashes(5, 0.95).unwrap(), vec!["wtw3s"]);
+        assert_eq!(fogmap.to_geohashes(4, 0.0).unwrap(), vec!["wtw3"]);
+        assert!(fogmap.to_geohashes(4, 0.5).unwrap().is_empty());
+
+        let mut fogmap = FogMap::new();
+        assert!(fogmap.add_geohashes(&["wtw3s", "wtwa"]).is_err());
+        assert!(fogmap.tiles.is_empty());
+    }
+}
@@ -0,0 +1,500 @@
+//! Import of whole folders of exploration data, from a directory or a `.tar`/`.tar.gz` archive.
+//!
+//! Each file is imported according to its name: data files of the `Fog of World` App, zip
+//! archives of them or Strava-like bulk exports, GPX, TCX, FIT, KML, KMZ, GeoJSON, NMEA and
+//! Google location histories, any of them possibly gzipped. Archives and subdirectories are
+//! walked recursively. CSV files are not, as their columns have to be configured.
+//!
+//! Hidden files are skipped, as are files of an unknown type. A file that fails to import is
+//! reported and does not stop the others.
+
+use super::filter::FilterOptions;
+use super::geojson::GeoJsonOptions;
+use super::kml::KmlOptions;
+use super::nmea::NmeaOptions;
+use super::strava::{ActivityFormat, StravaOptions};
+use super::takeout::TakeoutOptions;
+use super::{GapOptions, ImportSummary};
+use crate::datum::Datum;
+use crate::FogMap;
+use flate2::read::GzDecoder;
+#[cfg(feature = "native")]
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Cursor, Read};
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IngestOptions {
+    /// Where tracks are split instead of drawn, each format has its own default when unset.
+    pub gaps: Option<GapOptions>,
+    /// The datum of the coordinates in the files.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileKind {
+    /// A data file of the `Fog of World` App.
+    Fow,
+    /// A zip archive, reported as such when it could not be read.
+    Zip,
+    /// A zip archive of `Fog of World` data files.
+    FowZip,
+    /// A Strava-like bulk export of activities, reported as such when it could not be read.
+    ActivityExport,
+    /// An activity, alone or in a bulk export.
+    Activity(ActivityFormat),
+    Kml,
+    Kmz,
+    GeoJson,
+    /// A Google location history.
+    Takeout,
+    Nmea,
+    Tar,
+    Directory,
+}
+
+/// The import of one file. Archives and directories are only reported when they could not be
+/// read, their files are reported instead.
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    /// Path of the file, through the archives holding it.
+    pub path: String,
+    pub kind: FileKind,
+    pub result: Result<ImportSummary, String>,
+}
+
+// Whether a JSON document is a location history rather than GeoJSON, from its beginning.
+fn is_takeout(start: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(start);
+    start.trim_start().starts_with('[')
+        || start.contains("\"locations\"")
+        || start.contains("\"semanticSegments\"")
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+// The kind of a file from its lowercase name, `None` for the files that are not imported. A zip
+// archive or a JSON document is told apart by its content once read.
+fn file_kind(name: &str) -> Option<FileKind> {
+    if name.ends_with(".tar") || name.ends_with(".tgz") || name.ends_with(".tar.gz") {
+        return Some(FileKind::Tar);
+    }
+    if let Some(name) = name.strip_suffix(".gz") {
+        return file_kind(name);
+    }
+    let Some((_, extension)) = name.rsplit_once('.') else {
+        // `Fog of World` data files have no extension
+        return FogMap::parse_fow_file_name(name).map(|_| FileKind::Fow);
+    };
+    if let Some((format, _)) = ActivityFormat::from_file_name(name) {
+        return Some(FileKind::Activity(format));
+    }
+    match extension {
+        "kml" => Some(FileKind::Kml),
+        "kmz" => Some(FileKind::Kmz),
+        "json" | "geojson" => Some(FileKind::GeoJson),
+        "nmea" => Some(FileKind::Nmea),
+        "zip" => Some(FileKind::Zip),
+        _ => None,
+    }
+}
+
+struct Ingest<'a> {
+    fogmap: &'a mut FogMap,
+    options: &'a IngestOptions,
+    reports: Vec<FileReport>,
+}
+
+impl Ingest<'_> {
+    fn report(&mut self, path: &str, kind: FileKind, result: Result<ImportSummary, String>) {
+        self.reports.push(FileReport {
+            path: path.to_string(),
+            kind,
+            result,
+        });
+    }
+
+    fn gaps(&self, default: GapOptions) -> GapOptions {
+        self.options.gaps.unwrap_or(default)
+    }
+
+    // Imports a file according to `name`, which is its name without the extensions of the
+    // compressions already undone.
+    fn add(&mut self, path: &str, name: &str, reader: &mut dyn Read) {
+        let name = name.to_lowercase();
+        let IngestOptions { datum, filter, .. } = *self.options;
+        match file_kind(&name) {
+            None => {}
+            Some(FileKind::Tar) if name.ends_with(".tar") => self.add_tar(path, reader),
+            Some(FileKind::Tar) => self.add_tar(path, &mut GzDecoder::new(reader)),
+            Some(_) if name.ends_with(".gz") => {
+                let name = &name[..name.len() - ".gz".len()];
+                self.add(path, name, &mut GzDecoder::new(reader))
+            }
+            Some(FileKind::Fow) => {
+                let result = self
+                    .fogmap
+                    .add_fow_reader(&name, reader)
+                    .map(|delta| ImportSummary {
+                        delta,
+                        ..Default::default()
+                    });
+                self.report(path, FileKind::Fow, result);
+            }
+            Some(FileKind::Activity(format)) => {
+                let options = StravaOptions {
+                    gaps: self.gaps(GapOptions::default()),
+                    datum,
+                    filter,
+                };
+                let result = self.fogmap.add_activity(reader, format, &options);
+                self.report(path, FileKind::Activity(format), result);
+            }
+            Some(kind @ (FileKind::Kml | FileKind::Kmz)) => {
+                let options = KmlOptions {
+                    gaps: self.gaps(KmlOptions::default().gaps),
+                    datum,
+                    filter,
+                };
+                let result = if kind == FileKind::Kml {
+                    self.fogmap.add_kml(reader, &options)
+                } else {
+                    read_all(reader)
+                        .and_then(|data| self.fogmap.add_kmz(Cursor::new(data), &options))
+                };
+                self.report(path, kind, result);
+            }
+            Some(FileKind::GeoJson) => {
+                let mut reader = BufReader::new(reader);
+                let takeout = name.ends_with(".json") && reader.fill_buf().is_ok_and(is_takeout);
+                if takeout {
+                    let options = TakeoutOptions {
+                        gaps: self.gaps(TakeoutOptions::default().gaps),
+                        datum,
+                        filter,
+                        ..Default::default()
+                    };
+                    let result = self.fogmap.add_google_takeout(reader, &options);
+                    self.report(path, FileKind::Takeout, result);
+                } else {
+                    let options = GeoJsonOptions {
+                        gaps: self.gaps(GeoJsonOptions::default().gaps),
+                        datum,
+                        filter,
+                        ..Default::default()
+                    };
+                    let result = self.fogmap.add_geojson(reader, &options);
+                    self.report(path, FileKind::GeoJson, result);
+                }
+            }
+            Some(FileKind::Nmea) => {
+                let options = NmeaOptions {
+                    gaps: self.gaps(GapOptions::default()),
+                    datum,
+                    filter,
+                };
+                let result = self.fogmap.add_nmea(reader, &options);
+                self.report(path, FileKind::Nmea, result);
+            }
+            Some(FileKind::Zip) => self.add_zip(path, reader),
+            // only told from the content
+            Some(_) => {}
+        }
+    }
+
+    fn add_zip(&mut self, path: &str, reader: &mut dyn Read) {
+        let data = match read_all(reader) {
+            Ok(data) => data,
+            Err(e) => return self.report(path, FileKind::Zip, Err(e)),
+        };
+        let is_export = zip::ZipArchive::new(Cursor::new(&data)).is_ok_and(|archive| {
+            archive
+                .file_names()
+                .any(|name| name == "activities.csv" || name.starts_with("activities/"))
+        });
+        if !is_export {
+            let result = self
+                .fogmap
+                .add_fow_zip_reader(Cursor::new(data))
+                .map(|delta| ImportSummary {
+                    delta,
+                    ..Default::default()
+                });
+            return self.report(path, FileKind::FowZip, result);
+        }
+
+        let options = StravaOptions {
+            gaps: self.gaps(GapOptions::default()),
+            datum: self.options.datum,
+            filter: self.options.filter,
+        };
+        match self.fogmap.add_strava_zip(Cursor::new(data), &options) {
+            Ok(activities) => {
+                for activity in activities {
+                    let activity_path = format!("{}/{}", path, activity.file_name);
+                    self.report(
+                        &activity_path,
+                        FileKind::Activity(activity.format),
+                        activity.result,
+                    );
+                }
+            }
+            Err(e) => self.report(path, FileKind::ActivityExport, Err(e)),
+        }
+    }
+
+    fn add_tar(&mut self, path: &str, reader: &mut dyn Read) {
+        let mut archive = tar::Archive::new(reader);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(e) => return self.report(path, FileKind::Tar, Err(e.to_string())),
+        };
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                // the rest of the archive cannot be found after a broken entry
+                Err(e) => return self.report(path, FileKind::Tar, Err(e.to_string())),
+            };
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let entry_path = match entry.path() {
+                Ok(entry_path) => entry_path.to_string_lossy().into_owned(),
+                Err(e) => {
+                    self.report(path, FileKind::Tar, Err(e.to_string()));
+                    continue;
+                }
+            };
+            let components: Vec<&str> = entry_path
+                .split('/')
+                .filter(|component| !component.is_empty() && *component != ".")
+                .collect();
+            let Some(&name) = components.last() else {
+                continue;
+            };
+            if components.iter().any(|component| is_hidden(component)) {
+                continue;
+            }
+            let name = name.to_string();
+            self.add(
+                &format!("{}/{}", path, components.join("/")),
+                &name,
+                &mut entry,
+            );
+        }
+    }
+
+    // Imports a file or the files of a directory, `directories` being the directories already
+    // walked.
+    #[cfg(feature = "native")]
+    fn add_path(&mut self, path: &std::path::Path, directories: &mut HashSet<std::path::PathBuf>) {
+        let display = path.display().to_string();
+        if path.is_dir() {
+            // a directory reached again through a symbolic link is skipped, which also breaks
+            // the loops of links
+            match std::fs::canonicalize(path) {
+                Ok(canonical) => {
+                    if !directories.insert(canonical) {
+                        return;
+                    }
+                }
+                Err(e) => return self.report(&display, FileKind::Directory, Err(e.to_string())),
+            }
+            let entries = match std::fs::read_dir(path) {
+                Ok(entries) => entries,
+                Err(e) => return self.report(&display, FileKind::Directory, Err(e.to_string())),
+            };
+            let mut paths: Vec<_> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    !path
+                        .file_name()
+                        .is_some_and(|name| is_hidden(&name.to_string_lossy()))
+                })
+                .collect();
+            paths.sort();
+            for path in paths {
+                self.add_path(&path, directories);
+            }
+            return;
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match std::fs::File::open(path) {
+            Ok(file) => self.add(&display, &name, &mut BufReader::new(file)),
+            // reported only if the file would have been imported
+            Err(e) => {
+                if let Some(kind) = file_kind(&name.to_lowercase()) {
+                    self.report(&display, kind, Err(e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+fn read_all<R: Read>(mut reader: R) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    Ok(data)
+}
+
+impl FogMap {
+    /// Adds a file or an archive read from `reader`, its type being told from `file_name`.
+    pub fn ingest_reader<R: Read>(
+        &mut self,
+        file_name: &str,
+        mut reader: R,
+        options: &IngestOptions,
+    ) -> Vec<FileReport> {
+        let mut ingest = Ingest {
+            fogmap: self,
+            options,
+            reports: Vec::new(),
+        };
+        ingest.add(file_name, file_name, &mut reader);
+        ingest.reports
+    }
+
+    /// Adds a file, an archive or all the files of a directory and of its subdirectories.
+    #[cfg(feature = "native")]
+    pub fn ingest_path(
+        &mut self,
+        path: &std::path::Path,
+        options: &IngestOptions,
+    ) -> Vec<FileReport> {
+        let mut ingest = Ingest {
+            fogmap: self,
+            options,
+            reports: Vec::new(),
+        };
+        ingest.add_path(path, &mut HashSet::new());
+        ingest.reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+
+    const GPX: &str = r#"<gpx><trk><trkseg>
+        <trkpt lat="31.2" lon="121.5"/><trkpt lat="31.201" lon="121.501"/>
+    </trkseg></trk></gpx>"#;
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_ingest_tar() {
+        let fow = std::fs::read("tests/0921iihwtxn").unwrap();
+        let inner = tar(&[(
+            "history/Records.json",
+            br#"{"locations": [{"latitudeE7": 312000000, "longitudeE7": 1215000000}]}"#,
+        )]);
+        let archive = gzip(&tar(&[
+            ("./export/Sync/0921iihwtxn", &fow),
+            ("./export/tracks/a.gpx.gz", &gzip(GPX.as_bytes())),
+            ("./export/tracks/broken.gpx", b"<gpx></trk>"),
+            ("./export/.trash/b.gpx", GPX.as_bytes()),
+            ("./export/notes.txt", b"not a track"),
+            ("./export/inner.tar", &inner),
+        ]));
+
+        let mut fogmap = FogMap::new();
+        let reports =
+            fogmap.ingest_reader("export.tar.gz", &archive[..], &IngestOptions::default());
+        let kinds: Vec<_> = reports
+            .iter()
+            .map(|report| (report.path.as_str(), report.kind, report.result.is_ok()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("export.tar.gz/export/Sync/0921iihwtxn", FileKind::Fow, true),
+                (
+                    "export.tar.gz/export/tracks/a.gpx.gz",
+                    FileKind::Activity(ActivityFormat::Gpx),
+                    true
+                ),
+                (
+                    "export.tar.gz/export/tracks/broken.gpx",
+                    FileKind::Activity(ActivityFormat::Gpx),
+                    false
+                ),
+                (
+                    "export.tar.gz/export/inner.tar/history/Records.json",
+                    FileKind::Takeout,
+                    true
+                ),
+            ]
+        );
+        assert_eq!(reports[1].result.as_ref().unwrap().points, 2);
+        assert_eq!(reports[3].result.as_ref().unwrap().points, 1);
+
+        // a corrupt archive is reported, not a panic
+        let reports =
+            fogmap.ingest_reader("export.tar.gz", &archive[..100], &IngestOptions::default());
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kind, FileKind::Tar);
+        assert!(reports[0].result.is_err());
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_ingest_path() {
+        let root = std::env::temp_dir().join(format!("fogcore-ingest-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::create_dir_all(root.join(".hidden")).unwrap();
+        std::fs::write(root.join("a/b/track.gpx"), GPX).unwrap();
+        std::fs::write(root.join(".hidden/track.gpx"), GPX).unwrap();
+        std::fs::write(root.join("a/.track.gpx"), GPX).unwrap();
+        std::fs::write(root.join("a/1234abcdef"), b"not a tile").unwrap();
+        std::fs::write(
+            root.join("route.geojson"),
+            r#"{"type": "LineString", "coordinates": [[121.5, 31.2], [121.501, 31.201]]}"#,
+        )
+        .unwrap();
+
+        // a loop of links, the directory is only walked once
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", root.join("a/b/up")).unwrap();
+
+        let mut fogmap = FogMap::new();
+        let reports = fogmap.ingest_path(&root, &IngestOptions::default());
+        std::fs::remove_dir_all(&root).unwrap();
+        let kinds: Vec<_> = reports
+            .iter()
+            .map(|report| (report.kind, report.result.is_ok()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (FileKind::Activity(ActivityFormat::Gpx), true),
+                (FileKind::GeoJson, true)
+            ]
+        );
+        assert!(reports[0].path.ends_with("track.gpx"));
+    }
+}
@@ -0,0 +1,267 @@
+//! Import of KML files and KMZ archives, as exported by Google My Maps, Google Earth and many
+//! trackers.
+//!
+//! The geometries imported are `LineString`, `gx:Track` (also inside `gx:MultiTrack`) and
+//! `Point`. A KMZ archive is a zip file holding a `doc.kml`.
+
+use super::filter::FilterOptions;
+use super::{parse_time, GapOptions, ImportSummary, TrackPoint};
+use crate::datum::Datum;
+use crate::FogMap;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::{BufReader, Read, Seek};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct KmlOptions {
+    /// Where tracks are split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the file.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+impl Default for KmlOptions {
+    /// Splits `gx:Track`s on long pauses only: line strings are often drawn by hand, with far
+    /// apart vertices, and have no time.
+    fn default() -> Self {
+        Self {
+            gaps: GapOptions {
+                max_time_gap: Some(Duration::from_secs(15 * 60)),
+                max_distance_gap: None,
+            },
+            datum: Datum::Wgs84,
+            filter: FilterOptions::default(),
+        }
+    }
+}
+
+/// Reads the geometries of a KML file as segments, a `Point` becomes a segment of a single point.
+pub fn read_kml<R: Read>(reader: R) -> Result<Vec<Vec<TrackPoint>>, String> {
+    let mut reader = Reader::from_reader(BufReader::new(reader));
+    reader.config_mut().trim_text(true);
+
+    let mut segments = Vec::new();
+    // local names of the elements enclosing the current position
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut text = String::new();
+    // the times and points of the `gx:Track` being read
+    let mut track_times: Vec<Option<i64>> = Vec::new();
+    let mut track_points: Vec<Option<TrackPoint>> = Vec::new();
+
+    let mut buf = Vec::new();
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("Failed to read KML: {}", e))?
+        {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                if name == b"Track" {
+                    track_times.clear();
+                    track_points.clear();
+                }
+                path.push(name);
+                text.clear();
+            }
+            Event::Text(e) => {
+                let decoded = e
+                    .decode()
+                    .map_err(|e| format!("Failed to read KML: {}", e))?;
+                text.push_str(&decoded);
+            }
+            Event::End(e) => {
+                path.pop();
+                let parent = path.last().map(|name| name.as_slice());
+                match (e.local_name().as_ref(), parent) {
+                    (b"coordinates", Some(b"LineString")) => {
+                        segments.push(parse_coordinates(&text));
+                    }
+                    (b"coordinates", Some(b"Point")) => {
+                        segments.extend(parse_coordinates(&text).into_iter().map(|p| vec![p]));
+                    }
+                    (b"when", Some(b"Track")) => track_times.push(parse_time(&text)),
+                    (b"coord", Some(b"Track")) => track_points.push(parse_coord(&text)),
+                    (b"Track", _) => {
+                        // times and coordinates are paired by position, if they do match
+                        let times_match = track_times.len() == track_points.len();
+                        let segment = track_points
+                            .iter()
+                            .enumerate()
+                            .filter_map(|(i, point)| {
+                                let mut point = (*point)?;
+                                if times_match {
+                                    point.time = track_times[i];
+                                }
+                                Some(point)
+                            })
+                            .collect();
+                        segments.push(segment);
+                    }
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(segments)
+}
+
+// `lng,lat[,alt]` tuples separated by whitespace
+fn parse_coordinates(text: &str) -> Vec<TrackPoint> {
+    text.split_whitespace()
+        .filter_map(|tuple| {
+            let mut values = tuple.split(',').map(|value| value.trim().parse::<f64>());
+            match (values.next(), values.next()) {
+                (Some(Ok(lng)), Some(Ok(lat))) => {
+                    Some(TrackPoint::new(lng, lat)).filter(TrackPoint::is_valid)
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// `lng lat alt`, the content of a `gx:coord`
+fn parse_coord(text: &str) -> Option<TrackPoint> {
+    let mut values = text.split_whitespace().map(|value| value.parse::<f64>());
+    match (values.next(), values.next()) {
+        (Some(Ok(lng)), Some(Ok(lat))) => {
+            Some(TrackPoint::new(lng, lat)).filter(TrackPoint::is_valid)
+        }
+        _ => None,
+    }
+}
+
+impl FogMap {
+    /// Adds the tracks of a KML file.
+    pub fn add_kml<R: Read>(
+        &mut self,
+        reader: R,
+        options: &KmlOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut summary = ImportSummary::default();
+        for segment in read_kml(reader)? {
+            let segment: Vec<_> = segment
+                .into_iter()
+                .map(|point| point.to_wgs84(options.datum))
+                .collect();
+            self.add_track(&segment, &options.gaps, &options.filter, &mut summary);
+        }
+        Ok(summary)
+    }
+
+    /// Adds the tracks of a KMZ archive, read from its `doc.kml` or else from the first KML file
+    /// in the archive.
+    pub fn add_kmz<R: Read + Seek>(
+        &mut self,
+        reader: R,
+        options: &KmlOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut archive = match zip::ZipArchive::new(reader) {
+            Ok(archive) => archive,
+            Err(e) => return Err(format!("Failed to read zip file: {}", e)),
+        };
+
+        let name = match archive.index_for_name("doc.kml") {
+            Some(_) => "doc.kml".to_string(),
+            None => archive
+                .file_names()
+                .find(|name| name.to_lowercase().ends_with(".kml"))
+                .ok_or("No KML file in the KMZ archive")?
+                .to_string(),
+        };
+        let file = match archive.by_name(&name) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Failed to read {}: {}", name, e)),
+        };
+        self.add_kml(file, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    const KML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Document>
+    <Placemark>
+      <name>Walk</name>
+      <LineString>
+        <tessellate>1</tessellate>
+        <coordinates>
+          121.50,31.20,0 121.51,31.21,0
+          121.52,31.20,0
+        </coordinates>
+      </LineString>
+    </Placemark>
+    <Placemark>
+      <Point><coordinates>121.60,31.30</coordinates></Point>
+    </Placemark>
+    <Placemark>
+      <Polygon><outerBoundaryIs><LinearRing>
+        <coordinates>1,1 2,2 3,1 1,1</coordinates>
+      </LinearRing></outerBoundaryIs></Polygon>
+    </Placemark>
+    <Placemark>
+      <gx:MultiTrack>
+        <gx:Track>
+          <when>2024-05-01T08:00:00Z</when>
+          <when>2024-05-01T08:00:05Z</when>
+          <when>2024-05-01T10:00:00Z</when>
+          <gx:coord>121.700 31.400 10</gx:coord>
+          <gx:coord>121.701 31.401 10</gx:coord>
+          <gx:coord>121.702 31.402 10</gx:coord>
+        </gx:Track>
+      </gx:MultiTrack>
+    </Placemark>
+  </Document>
+</kml>"#;
+
+    #[test]
+    fn test_read_kml() {
+        let segments = read_kml(KML.as_bytes()).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].len(), 3);
+        assert_eq!(segments[0][1], TrackPoint::new(121.51, 31.21));
+        assert_eq!(segments[1], vec![TrackPoint::new(121.60, 31.30)]);
+        assert_eq!(
+            segments[2][1],
+            TrackPoint::with_time(121.701, 31.401, 1714550405000)
+        );
+    }
+
+    #[test]
+    fn test_add_kmz() {
+        let mut data = Vec::new();
+        {
+            let mut writer = zip::ZipWriter::new(Cursor::new(&mut data));
+            writer
+                .start_file("doc.kml", zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(KML.as_bytes()).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let mut fogmap = FogMap::new();
+        let summary = fogmap
+            .add_kmz(Cursor::new(&data), &KmlOptions::default())
+            .unwrap();
+        assert_eq!(summary.points, 7);
+        // the track is split by its two hours pause
+        assert_eq!(summary.skipped_jumps, 1);
+        assert_eq!(summary.segments, 4);
+        assert!(!summary.delta.is_empty());
+
+        assert!(fogmap
+            .add_kmz(Cursor::new(KML.as_bytes()), &KmlOptions::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Importers for track formats other than the `Fog of World` data.
+//!
+//! The importers read from any [`std::io::Read`], so they work on files as well as on in-memory
+//! buffers in the wasm build. Tracks are drawn into a [`FogMap`] with [`FogMap::add_line`].
+
+pub mod csv;
+pub mod filter;
+pub mod fit;
+pub mod geojson;
+pub mod gpx;
+pub mod grid;
+pub mod ingest;
+pub mod kml;
+pub mod nmea;
+pub mod photo;
+pub mod raster;
+pub mod strava;
+pub mod takeout;
+pub mod tcx;
+
+use crate::datum::Datum;
+use crate::utils::haversine_distance;
+use crate::{ExplorationDelta, FogMap};
+use chrono::{DateTime, NaiveDateTime};
+use filter::{filter_track, FilterOptions};
+use std::time::Duration;
+
+/// A recorded location.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub lng: f64,
+    pub lat: f64,
+    /// Milliseconds since the Unix epoch, if known.
+    pub time: Option<i64>,
+    /// Radius of uncertainty in meters, if known.
+    pub accuracy: Option<f64>,
+}
+
+impl TrackPoint {
+    pub fn new(lng: f64, lat: f64) -> Self {
+        Self {
+            lng,
+            lat,
+            time: None,
+            accuracy: None,
+        }
+    }
+
+    pub fn with_time(lng: f64, lat: f64, time: i64) -> Self {
+        Self {
+            lng,
+            lat,
+            time: Some(time),
+            accuracy: None,
+        }
+    }
+
+    /// Converts the point from `datum` to WGS-84.
+    pub fn to_wgs84(self, datum: Datum) -> Self {
+        let (lng, lat) = datum.to_wgs84(self.lng, self.lat);
+        Self { lng, lat, ..self }
+    }
+
+    fn is_valid(&self) -> bool {
+        (-180.0..=180.0).contains(&self.lng) && (-90.0..=90.0).contains(&self.lat)
+    }
+}
+
+/// When two consecutive points of a track are too far apart to be joined by a line.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GapOptions {
+    /// Maximum time between two points. Points without a time are never split on time.
+    pub max_time_gap: Option<Duration>,
+    /// Maximum distance between two points, in meters.
+    pub max_distance_gap: Option<f64>,
+}
+
+impl Default for GapOptions {
+    fn default() -> Self {
+        Self {
+            max_time_gap: Some(Duration::from_secs(15 * 60)),
+            max_distance_gap: Some(5_000.0),
+        }
+    }
+}
+
+impl GapOptions {
+    /// Never split a track.
+    pub fn none() -> Self {
+        Self {
+            max_time_gap: None,
+            max_distance_gap: None,
+        }
+    }
+
+    pub fn is_gap(&self, a: &TrackPoint, b: &TrackPoint) -> bool {
+        if let (Some(max_gap), Some(time_a), Some(time_b)) = (self.max_time_gap, a.time, b.time) {
+            if (time_b - time_a).unsigned_abs() > max_gap.as_millis() as u64 {
+                return true;
+            }
+        }
+        if let Some(max_distance) = self.max_distance_gap {
+            if haversine_distance(a.lng, a.lat, b.lng, b.lat) > max_distance {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// What an import has read and drawn.
+#[derive(Debug, Default, Clone)]
+pub struct ImportSummary {
+    /// Number of track points read.
+    pub points: usize,
+    /// Number of points read but not drawn, for instance because they are inaccurate.
+    pub dropped_points: usize,
+    /// Number of continuous segments drawn, after splitting at gaps.
+    pub segments: usize,
+    /// Number of gaps between consecutive points that were not drawn.
+    pub skipped_jumps: usize,
+    /// What the import changed in the FogMap.
+    pub delta: ExplorationDelta,
+    /// Problems in the input that were skipped over, such as malformed lines.
+    pub errors: Vec<String>,
+}
+
+impl ImportSummary {
+    /// Adds the numbers of another import to this one.
+    pub fn merge(&mut self, other: ImportSummary) {
+        self.points += other.points;
+        self.dropped_points += other.dropped_points;
+        self.segments += other.segments;
+        self.skipped_jumps += other.skipped_jumps;
+        self.delta.merge(other.delta);
+        self.errors.extend(other.errors);
+    }
+}
+
+// Number of points the filter is applied to at once, which bounds the memory used by a long
+// segment. Noise across two chunks may go through the filter.
+const FILTER_CHUNK: usize = 1 << 16;
+
+/// Draws a track point by point, so that importers can stream large files.
+///
+/// Consecutive points are joined by a line, unless the gap between them is too large, in which
+/// case a new segment starts. A segment made of a single point is drawn as that point.
+///
+/// With a filter, points are held back until the segment ends and then go through the filter.
+pub struct TrackWriter<'a> {
+    fogmap: &'a mut FogMap,
+    gaps: GapOptions,
+    filter: FilterOptions,
+    datum: Datum,
+    // points waiting for the filter
+    pending: Vec<TrackPoint>,
+    last: Option<TrackPoint>,
+    // number of points in the current segment
+    segment_len: usize,
+    summary: ImportSummary,
+}
+
+impl<'a> TrackWriter<'a> {
+    pub fn new(fogmap: &'a mut FogMap, gaps: GapOptions) -> Self {
+        Self {
+            fogmap,
+            gaps,
+            filter: FilterOptions::default(),
+            datum: Datum::Wgs84,
+            pending: Vec::new(),
+            last: None,
+            segment_len: 0,
+            summary: ImportSummary::default(),
+        }
+    }
+
+    /// Filters the noise of the track before it is drawn.
+    pub fn with_filter(mut self, filter: FilterOptions) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Converts the points from `datum` to WGS-84 before they are drawn.
+    pub fn with_datum(mut self, datum: Datum) -> Self {
+        self.datum = datum;
+        self
+    }
+
+    /// Adds the next point of the track.
+    pub fn push(&mut self, point: TrackPoint) {
+        self.summary.points += 1;
+        let point = point.to_wgs84(self.datum);
+        if !self.filter.is_enabled() {
+            self.draw(point);
+            return;
+        }
+        // long pauses end the segment already, spikes are only caught within a segment
+        let pause = GapOptions {
+            max_time_gap: self.gaps.max_time_gap,
+            max_distance_gap: None,
+        };
+        if self
+            .pending
+            .last()
+            .is_some_and(|last| pause.is_gap(last, &point))
+        {
+            self.summary.skipped_jumps += 1;
+            self.end_segment();
+        }
+        self.pending.push(point);
+        if self.pending.len() >= FILTER_CHUNK {
+            self.flush();
+        }
+    }
+
+    // Draws the pending points through the filter.
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let segments = filter_track(&self.pending, &self.filter);
+        let kept: usize = segments.iter().map(Vec::len).sum();
+        self.summary.dropped_points += self.pending.len() - kept;
+        self.pending.clear();
+        for (i, segment) in segments.into_iter().enumerate() {
+            if i > 0 {
+                self.summary.skipped_jumps += 1;
+                self.end_drawn_segment();
+            }
+            for point in segment {
+                self.draw(point);
+            }
+        }
+    }
+
+    fn draw(&mut self, point: TrackPoint) {
+        if let Some(last) = self.last {
+            if self.gaps.is_gap(&last, &point) {
+                self.summary.skipped_jumps += 1;
+                self.end_drawn_segment();
+            } else {
+                self.summary.delta.merge(
+                    self.fogmap
+                        .add_line(last.lng, last.lat, point.lng, point.lat),
+                );
+            }
+        }
+        self.last = Some(point);
+        self.segment_len += 1;
+    }
+
+    /// Counts a point that was read but is not part of the track.
+    pub fn drop_point(&mut self) {
+        self.summary.points += 1;
+        self.summary.dropped_points += 1;
+    }
+
+    /// Reports a problem in the input that was skipped over.
+    pub fn report_error(&mut self, error: String) {
+        self.summary.errors.push(error);
+    }
+
+    /// Ends the current segment, the next point starts a new one.
+    pub fn end_segment(&mut self) {
+        self.flush();
+        self.end_drawn_segment();
+    }
+
+    fn end_drawn_segment(&mut self) {
+        if let (1, Some(last)) = (self.segment_len, self.last) {
+            self.summary
+                .delta
+                .merge(self.fogmap.add_point(last.lng, last.lat));
+        }
+        if self.segment_len > 0 {
+            self.summary.segments += 1;
+        }
+        self.last = None;
+        self.segment_len = 0;
+    }
+
+    pub fn finish(mut self) -> ImportSummary {
+        self.end_segment();
+        self.summary
+    }
+}
+
+impl FogMap {
+    /// Draws a track by joining its consecutive points with lines, except where `gaps` tells
+    /// the points are too far apart. A segment made of a single point is drawn as that point.
+    /// The track goes through `filter` first.
+    pub fn add_track(
+        &mut self,
+        points: &[TrackPoint],
+        gaps: &GapOptions,
+        filter: &FilterOptions,
+        summary: &mut ImportSummary,
+    ) {
+        let mut writer = TrackWriter::new(self, *gaps).with_filter(*filter);
+        for point in points {
+            writer.push(*point);
+        }
+        summary.merge(writer.finish());
+    }
+}
+
+/// Parses a timestamp as found in track files, either RFC 3339 or the same without a time zone,
+/// which is taken as UTC. Returns milliseconds since the Unix epoch.
+pub fn parse_time(time: &str) -> Option<i64> {
+    let time = time.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.timestamp_millis());
+    }
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .map(|time| time.and_utc().timestamp_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_writer_filter() {
+        let mut points: Vec<TrackPoint> = (0..6)
+            .map(|i| TrackPoint::with_time(121.5, 31.2 + i as f64 * 1e-4, i * 10_000))
+            .collect();
+        // a spike, then a pause
+        points[2].lng += 0.05;
+        points[5].time = Some(3_600_000);
+
+        let mut fogmap = FogMap::new();
+        let mut summary = ImportSummary::default();
+        let filter = FilterOptions::recommended();
+        fogmap.add_track(&points, &GapOptions::default(), &filter, &mut summary);
+        assert_eq!(summary.points, 6);
+        // the spike, and the two points simplified away from the straight line
+        assert_eq!(summary.dropped_points, 3);
+        assert_eq!(summary.skipped_jumps, 1);
+        assert_eq!(summary.segments, 2);
+
+        let mut unfiltered = ImportSummary::default();
+        FogMap::new().add_track(
+            &points,
+            &GapOptions::default(),
+            &FilterOptions::default(),
+            &mut unfiltered,
+        );
+        assert!(unfiltered.delta.new_pixels > summary.delta.new_pixels);
+    }
+
+    #[test]
+    fn test_track_writer_datum() {
+        let mut fogmap = FogMap::new();
+        let mut writer =
+            TrackWriter::new(&mut fogmap, GapOptions::default()).with_datum(Datum::Gcj02);
+        writer.push(TrackPoint::new(121.4737, 31.2304));
+        let delta = writer.finish().delta;
+
+        let (lng, lat) = Datum::Gcj02.to_wgs84(121.4737, 31.2304);
+        assert_eq!(
+            FogMap::new().add_point(lng, lat).new_blocks,
+            delta.new_blocks
+        );
+        // the offset of about 500 meters moves the point to another block
+        assert_ne!(
+            FogMap::new().add_point(121.4737, 31.2304).new_blocks,
+            delta.new_blocks
+        );
+    }
+}
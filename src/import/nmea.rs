@@ -0,0 +1,252 @@
+//! Import of NMEA 0183 logs, as written by dashcams, marine GPS units and loggers.
+//!
+//! Fixes are read from the `RMC` and `GGA` sentences of any talker (`$GPRMC`, `$GNRMC`,
+//! `$GPGGA`...), other sentences are ignored. A malformed sentence or a bad checksum is reported
+//! in the summary and skipped, it does not fail the whole log.
+
+use super::filter::FilterOptions;
+use super::{GapOptions, ImportSummary, TrackPoint, TrackWriter};
+use crate::datum::Datum;
+use crate::FogMap;
+use chrono::NaiveDate;
+use std::io::{BufRead, BufReader, Read};
+
+#[derive(Debug, Clone, Default)]
+pub struct NmeaOptions {
+    /// Where the log is split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the file.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+// A position read from a sentence.
+struct Fix {
+    point: TrackPoint,
+    // milliseconds since midnight UTC
+    time_of_day: Option<i64>,
+    valid: bool,
+}
+
+// Checks the checksum of a sentence and returns its fields.
+fn split_sentence(line: &str) -> Result<Vec<&str>, String> {
+    let body = line
+        .strip_prefix('$')
+        .ok_or("sentence does not start with '$'")?;
+    let (body, checksum) = body.split_once('*').ok_or("missing checksum")?;
+    let expected = u8::from_str_radix(checksum.trim(), 16)
+        .map_err(|_| format!("invalid checksum '{}'", checksum.trim()))?;
+    let actual = body.bytes().fold(0, |acc, byte| acc ^ byte);
+    if actual != expected {
+        return Err(format!(
+            "checksum mismatch, expected {:02X} but computed {:02X}",
+            expected, actual
+        ));
+    }
+    Ok(body.split(',').collect())
+}
+
+// `ddmm.mmmm` or `dddmm.mmmm` with a hemisphere
+fn parse_coordinate(value: &str, hemisphere: &str) -> Result<f64, String> {
+    let raw: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid coordinate '{}'", value))?;
+    let degrees = (raw / 100.0).trunc() + (raw % 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Ok(degrees),
+        "S" | "W" => Ok(-degrees),
+        _ => Err(format!("invalid hemisphere '{}'", hemisphere)),
+    }
+}
+
+// `hhmmss.sss` as milliseconds since midnight
+fn parse_time_of_day(value: &str) -> Option<i64> {
+    if value.len() < 6 {
+        return None;
+    }
+    let hours: i64 = value.get(0..2)?.parse().ok()?;
+    let minutes: i64 = value.get(2..4)?.parse().ok()?;
+    let seconds: f64 = value.get(4..)?.parse().ok()?;
+    Some((hours * 3600 + minutes * 60) * 1000 + (seconds * 1000.0).round() as i64)
+}
+
+// `ddmmyy` as milliseconds since the Unix epoch at midnight
+fn parse_date(value: &str) -> Option<i64> {
+    if value.len() != 6 || !value.is_ascii() {
+        return None;
+    }
+    let day = value[0..2].parse().ok()?;
+    let month = value[2..4].parse().ok()?;
+    let year: i32 = value[4..6].parse().ok()?;
+    let year = if year < 80 { 2000 + year } else { 1900 + year };
+    let date = NaiveDate::from_ymd_opt(year, month, day)?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+// Parses a `RMC` or `GGA` sentence, returns `None` for other sentences. The date of a `RMC`
+// sentence is stored into `date`.
+fn parse_fix(fields: &[&str], date: &mut Option<i64>) -> Result<Option<Fix>, String> {
+    let sentence = fields[0];
+    if sentence.len() != 5 {
+        return Ok(None);
+    }
+    // (index of the latitude, whether the fix is valid)
+    let (lat_index, valid) = match sentence.get(2..).unwrap_or_default() {
+        "RMC" if fields.len() >= 10 => {
+            if let Some(rmc_date) = parse_date(fields[9]) {
+                *date = Some(rmc_date);
+            }
+            (3, fields[2] == "A")
+        }
+        "GGA" if fields.len() >= 7 => (2, !matches!(fields[6], "" | "0")),
+        "RMC" | "GGA" => return Err(format!("too few fields in {}", sentence)),
+        _ => return Ok(None),
+    };
+    let time_of_day = parse_time_of_day(fields[1]);
+    if !valid || fields[lat_index].is_empty() {
+        return Ok(Some(Fix {
+            point: TrackPoint::new(0.0, 0.0),
+            time_of_day,
+            valid: false,
+        }));
+    }
+    let lat = parse_coordinate(fields[lat_index], fields[lat_index + 1])?;
+    let lng = parse_coordinate(fields[lat_index + 2], fields[lat_index + 3])?;
+    let mut point = TrackPoint::new(lng, lat);
+    if !point.is_valid() {
+        return Err(format!("coordinates out of range: {}, {}", lat, lng));
+    }
+    point.time = date.zip(time_of_day).map(|(date, time)| date + time);
+    Ok(Some(Fix {
+        point,
+        time_of_day,
+        valid: true,
+    }))
+}
+
+impl FogMap {
+    /// Adds the fixes of a NMEA 0183 log, joining consecutive valid fixes with lines.
+    pub fn add_nmea<R: Read>(
+        &mut self,
+        reader: R,
+        options: &NmeaOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut writer = TrackWriter::new(self, options.gaps)
+            .with_filter(options.filter)
+            .with_datum(options.datum);
+        let mut date = None;
+        // receivers usually send both a RMC and a GGA sentence for each fix, either may be
+        // valid when the other is not
+        let mut last_time_of_day = None;
+        let mut last_accepted_time_of_day = None;
+
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        loop {
+            line.clear();
+            if reader
+                .read_until(b'\n', &mut line)
+                .map_err(|e| format!("Failed to read NMEA log: {}", e))?
+                == 0
+            {
+                break;
+            }
+            line_number += 1;
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            match split_sentence(text).and_then(|fields| parse_fix(&fields, &mut date)) {
+                Ok(Some(fix)) => {
+                    let seen =
+                        |last: Option<i64>| fix.time_of_day.is_some() && fix.time_of_day == last;
+                    if fix.valid && !seen(last_accepted_time_of_day) {
+                        writer.push(fix.point);
+                        last_accepted_time_of_day = fix.time_of_day;
+                    } else if !fix.valid && !seen(last_time_of_day) {
+                        writer.drop_point();
+                    }
+                    last_time_of_day = fix.time_of_day;
+                }
+                Ok(None) => {}
+                Err(e) => writer.report_error(format!("line {}: {}", line_number, e)),
+            }
+        }
+        Ok(writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+$GPGGA,080000.00,3112.0000,N,12130.0000,E,1,08,0.9,10.0,M,0.0,M,,*64
+$GPRMC,080000.00,A,3112.0000,N,12130.0000,E,10.0,90.0,010524,,,A*5C
+$GPGSV,3,1,12,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*7F
+$GPRMC,080001.00,A,3112.0600,N,12130.0600,E,10.0,90.0,010524,,,A*5D
+$GPRMC,080002.00,V,,,,,,,010524,,,N*75
+$GNGGA,080003.00,3112.1200,N,12130.1200,E,0,00,99.9,,,,,,*71
+$GNRMC,080004.00,A,3112.1800,N,12130.1800,E,10.0,90.0,010524,,,A*46
+$GPRMC,080005.00,A,3112.2400,N,12130.2400,E,10.0,90.0,010524,,,A*00
+garbage
+$GPRMC,080006.00,A,3112.30
+";
+
+    #[test]
+    fn test_parse_fix() {
+        let mut date = None;
+        let fields = split_sentence(LOG.lines().nth(1).unwrap()).unwrap();
+        let fix = parse_fix(&fields, &mut date).unwrap().unwrap();
+        assert!(fix.valid);
+        assert_eq!(fix.point.lat, 31.2);
+        assert_eq!(fix.point.lng, 121.5);
+        assert_eq!(fix.point.time, Some(1714550400000));
+
+        let fields = split_sentence(LOG.lines().nth(5).unwrap()).unwrap();
+        assert!(!parse_fix(&fields, &mut date).unwrap().unwrap().valid);
+    }
+
+    #[test]
+    fn test_add_nmea() {
+        let mut fogmap = FogMap::new();
+        let summary = fogmap
+            .add_nmea(LOG.as_bytes(), &NmeaOptions::default())
+            .unwrap();
+        // the GGA and RMC of the first fix are only counted once
+        assert_eq!(summary.points, 5);
+        assert_eq!(summary.dropped_points, 2);
+        assert_eq!(summary.segments, 1);
+        assert!(!summary.delta.is_empty());
+        // the bad checksum, the garbage and the truncated sentence
+        assert_eq!(summary.errors.len(), 3);
+        assert!(summary.errors[0].starts_with("line 8: checksum mismatch"));
+    }
+
+    #[test]
+    fn test_add_nmea_edge_cases() {
+        // an invalid GGA followed by a valid RMC of the same fix
+        let log = "\
+$GPGGA,080000.00,,,,,0,00,99.9,,,,,,*57
+$GPRMC,080000.00,A,3112.0000,N,12130.0000,E,10.0,90.0,010524,,,A*5C
+";
+        let summary = FogMap::new()
+            .add_nmea(log.as_bytes(), &NmeaOptions::default())
+            .unwrap();
+        assert_eq!(summary.points - summary.dropped_points, 1);
+        assert!(summary.errors.is_empty());
+
+        // characters spanning several bytes where ASCII is expected
+        for fields in ["G\u{e9}MC,080000.00", "GPRMC,1\u{e9}345.00,V,,,,,,,010524"] {
+            let checksum = fields.bytes().fold(0, |acc, byte| acc ^ byte);
+            let log = format!("${}*{:02X}\n", fields, checksum);
+            assert!(FogMap::new()
+                .add_nmea(log.as_bytes(), &NmeaOptions::default())
+                .is_ok());
+        }
+    }
+}
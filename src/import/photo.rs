@@ -0,0 +1,398 @@
+//! Import of the locations of geotagged photos, read from their EXIF GPS tags.
+//!
+//! JPEG, HEIC, TIFF, PNG and WebP are supported. Each location is stamped as a circle, and photos
+//! taken shortly one after the other can be joined by lines.
+
+use super::filter::{filter_track, FilterOptions};
+use super::{ImportSummary, TrackPoint};
+use crate::datum::Datum;
+use crate::FogMap;
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Tag, Value};
+use std::io::{BufRead, Cursor, Seek};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct PhotoOptions {
+    /// Radius in meters of the area visited around a photo.
+    pub radius: f64,
+    /// Joins the photos taken within this time of each other with a line.
+    pub connect_within: Option<Duration>,
+    /// The datum of the GPS tags, some phones sold in China write GCJ-02.
+    pub datum: Datum,
+    /// See [`FilterOptions`]. The photos with a time are filtered as a track, in the order they
+    /// were taken, and those removed are not stamped.
+    pub filter: FilterOptions,
+}
+
+impl Default for PhotoOptions {
+    fn default() -> Self {
+        Self {
+            radius: 100.0,
+            connect_within: None,
+            datum: Datum::Wgs84,
+            filter: FilterOptions::default(),
+        }
+    }
+}
+
+fn rationals(exif: &Exif, tag: Tag) -> Option<Vec<f64>> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => Some(values.iter().map(|value| value.to_f64()).collect()),
+        _ => None,
+    }
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<&[u8]> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
+
+// degrees, minutes and seconds, negated for the south and west references
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag) -> Option<f64> {
+    let values = rationals(exif, tag)?;
+    let degrees = values.first()?
+        + values.get(1).unwrap_or(&0.0) / 60.0
+        + values.get(2).unwrap_or(&0.0) / 3600.0;
+    match ascii(exif, reference).and_then(|reference| reference.first()) {
+        Some(b'S' | b'W') => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+// The GPS time is in UTC, the time the photo was taken is local unless it has an offset.
+fn time(exif: &Exif) -> Option<i64> {
+    let gps_time = || {
+        let date = std::str::from_utf8(ascii(exif, Tag::GPSDateStamp)?).ok()?;
+        let date = NaiveDate::parse_from_str(date.trim_end_matches('\0'), "%Y:%m:%d").ok()?;
+        let time = rationals(exif, Tag::GPSTimeStamp)?;
+        let seconds = time.first()? * 3600.0 + time.get(1)? * 60.0 + time.get(2)?;
+        let midnight = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis();
+        Some(midnight + (seconds * 1000.0).round() as i64)
+    };
+    let original_time = || {
+        let mut time = exif::DateTime::from_ascii(ascii(exif, Tag::DateTimeOriginal)?).ok()?;
+        if let Some(offset) = ascii(exif, Tag::OffsetTimeOriginal) {
+            time.parse_offset(offset).ok()?;
+        }
+        let naive = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(time.year as i32, time.month as u32, time.day as u32)?,
+            chrono::NaiveTime::from_hms_opt(
+                time.hour as u32,
+                time.minute as u32,
+                time.second as u32,
+            )?,
+        );
+        let offset = time.offset.unwrap_or(0) as i64 * 60_000;
+        Some(naive.and_utc().timestamp_millis() - offset)
+    };
+    gps_time().or_else(original_time)
+}
+
+/// Reads the location and time of a photo, `None` if it is not geotagged.
+pub fn read_photo_location<R: BufRead + Seek>(
+    reader: &mut R,
+) -> Result<Option<TrackPoint>, String> {
+    let exif = match exif::Reader::new().read_from_container(reader) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(format!("Failed to read EXIF: {}", e)),
+    };
+    let lat = coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef);
+    let lng = coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef);
+    let (Some(lat), Some(lng)) = (lat, lng) else {
+        return Ok(None);
+    };
+    let point = TrackPoint {
+        time: time(&exif),
+        ..TrackPoint::new(lng, lat)
+    };
+    // cameras without a fix often write zeros
+    Ok(Some(point).filter(|point| point.is_valid() && (point.lat, point.lng) != (0.0, 0.0)))
+}
+
+impl FogMap {
+    /// Stamps the locations of photos, and joins those taken close in time when asked.
+    pub fn add_photo_locations(
+        &mut self,
+        points: &[TrackPoint],
+        options: &PhotoOptions,
+    ) -> ImportSummary {
+        let mut summary = ImportSummary {
+            points: points.len(),
+            ..Default::default()
+        };
+        let mut points: Vec<TrackPoint> = points
+            .iter()
+            .map(|point| point.to_wgs84(options.datum))
+            .collect();
+        if options.filter.is_enabled() {
+            let (mut timed, untimed): (Vec<TrackPoint>, Vec<TrackPoint>) =
+                points.into_iter().partition(|point| point.time.is_some());
+            timed.sort_by_key(|point| point.time);
+            let kept: Vec<TrackPoint> = filter_track(&timed, &options.filter)
+                .into_iter()
+                .flatten()
+                .chain(untimed)
+                .collect();
+            summary.dropped_points += summary.points - kept.len();
+            points = kept;
+        }
+        for point in &points {
+            summary
+                .delta
+                .merge(self.add_circle(point.lng, point.lat, options.radius));
+        }
+
+        if let Some(connect_within) = options.connect_within {
+            let mut timed: Vec<(i64, &TrackPoint)> = points
+                .iter()
+                .filter_map(|point| Some((point.time?, point)))
+                .collect();
+            timed.sort_by_key(|(time, _)| *time);
+            let mut connected = false;
+            for pair in timed.windows(2) {
+                let ((time_a, a), (time_b, b)) = (pair[0], pair[1]);
+                if (time_b - time_a) as u128 <= connect_within.as_millis() {
+                    summary
+                        .delta
+                        .merge(self.add_line(a.lng, a.lat, b.lng, b.lat));
+                    if !connected {
+                        summary.segments += 1;
+                    }
+                    connected = true;
+                } else {
+                    connected = false;
+                }
+            }
+        }
+        summary
+    }
+
+    /// Adds the locations of photos held in memory, such as files picked in a browser.
+    pub fn add_photo_buffers<B: AsRef<[u8]>>(
+        &mut self,
+        buffers: &[B],
+        options: &PhotoOptions,
+    ) -> ImportSummary {
+        let locations = buffers.iter().enumerate().map(|(i, buffer)| {
+            let location = read_photo_location(&mut Cursor::new(buffer.as_ref()));
+            (format!("photo {}", i), location)
+        });
+        self.add_photo_results(locations, options)
+    }
+
+    /// Adds the locations of the photos of a directory and of its subdirectories.
+    #[cfg(feature = "native")]
+    pub fn add_photo_directory(
+        &mut self,
+        path: &std::path::Path,
+        options: &PhotoOptions,
+    ) -> Result<ImportSummary, String> {
+        const EXTENSIONS: [&str; 9] = [
+            "jpg", "jpeg", "heic", "heif", "avif", "tif", "tiff", "png", "webp",
+        ];
+
+        let mut locations = Vec::new();
+        let mut directories = vec![path.to_path_buf()];
+        // a directory reached again through a symbolic link is skipped, which also breaks the
+        // loops of links
+        let mut walked = std::collections::HashSet::new();
+        while let Some(directory) = directories.pop() {
+            let canonical = std::fs::canonicalize(&directory)
+                .map_err(|e| format!("Failed to read {}: {}", directory.display(), e))?;
+            if !walked.insert(canonical) {
+                continue;
+            }
+            let entries = std::fs::read_dir(&directory)
+                .map_err(|e| format!("Failed to read {}: {}", directory.display(), e))?;
+            let mut paths: Vec<_> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect();
+            paths.sort();
+            for path in paths {
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let is_photo = path
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| EXTENSIONS.contains(&&*extension.to_lowercase()));
+                if !is_photo {
+                    continue;
+                }
+                let location = std::fs::File::open(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| read_photo_location(&mut std::io::BufReader::new(file)));
+                locations.push((path.display().to_string(), location));
+            }
+        }
+        Ok(self.add_photo_results(locations.into_iter(), options))
+    }
+
+    // Adds the locations read from named photos, counting those without a location and
+    // reporting those that could not be read.
+    fn add_photo_results(
+        &mut self,
+        locations: impl Iterator<Item = (String, Result<Option<TrackPoint>, String>)>,
+        options: &PhotoOptions,
+    ) -> ImportSummary {
+        let mut points = Vec::new();
+        let mut errors = Vec::new();
+        let mut dropped_points = 0;
+        for (name, location) in locations {
+            match location {
+                Ok(Some(point)) => points.push(point),
+                Ok(None) => dropped_points += 1,
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
+        let mut summary = self.add_photo_locations(&points, options);
+        summary.points += dropped_points;
+        summary.dropped_points += dropped_points;
+        summary.errors = errors;
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::filter::SpikeOptions;
+    use exif::{Field, Rational};
+
+    // A JPEG holding only an EXIF segment, enough for the EXIF reader.
+    fn jpeg(fields: &[Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn field(tag: Tag, value: Value) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        }
+    }
+
+    fn geotagged(lat: f64, lng: f64, time: &str) -> Vec<u8> {
+        let dms = |value: f64| {
+            let value = value.abs();
+            let minutes = value.fract() * 60.0;
+            Value::Rational(vec![
+                Rational::from((value as u32, 1)),
+                Rational::from((minutes as u32, 1)),
+                Rational::from(((minutes.fract() * 60.0 * 1000.0).round() as u32, 1000)),
+            ])
+        };
+        let reference = |value: f64, positive: &[u8], negative: &[u8]| {
+            let reference = if value < 0.0 { negative } else { positive };
+            Value::Ascii(vec![reference.to_vec()])
+        };
+        jpeg(&[
+            field(Tag::GPSLatitudeRef, reference(lat, b"N", b"S")),
+            field(Tag::GPSLatitude, dms(lat)),
+            field(Tag::GPSLongitudeRef, reference(lng, b"E", b"W")),
+            field(Tag::GPSLongitude, dms(lng)),
+            field(Tag::DateTimeOriginal, Value::Ascii(vec![time.into()])),
+            field(
+                Tag::OffsetTimeOriginal,
+                Value::Ascii(vec![b"+08:00".to_vec()]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_read_photo_location() {
+        let photo = geotagged(-31.5, 121.25, "2024:05:01 16:00:00");
+        let point = read_photo_location(&mut Cursor::new(photo))
+            .unwrap()
+            .unwrap();
+        assert!((point.lat + 31.5).abs() < 1e-9);
+        assert!((point.lng - 121.25).abs() < 1e-9);
+        assert_eq!(point.time, Some(1714550400000));
+
+        let untagged = jpeg(&[field(Tag::Make, Value::Ascii(vec![b"Camera".to_vec()]))]);
+        assert_eq!(read_photo_location(&mut Cursor::new(untagged)), Ok(None));
+        assert!(read_photo_location(&mut Cursor::new(b"not a photo")).is_err());
+    }
+
+    #[test]
+    fn test_add_photo_buffers() {
+        let photos = vec![
+            geotagged(31.2, 121.5, "2024:05:01 16:00:00"),
+            geotagged(31.21, 121.51, "2024:05:01 16:05:00"),
+            geotagged(31.3, 121.6, "2024:05:01 18:00:00"),
+            jpeg(&[field(Tag::Make, Value::Ascii(vec![b"Camera".to_vec()]))]),
+            b"not a photo".to_vec(),
+        ];
+        let mut fogmap = FogMap::new();
+        let summary = fogmap.add_photo_buffers(&photos, &PhotoOptions::default());
+        assert_eq!(summary.points, 4);
+        assert_eq!(summary.dropped_points, 1);
+        assert_eq!(summary.segments, 0);
+        assert_eq!(summary.errors.len(), 1);
+
+        let options = PhotoOptions {
+            radius: 0.0,
+            connect_within: Some(Duration::from_secs(10 * 60)),
+            ..Default::default()
+        };
+        let summary = FogMap::new().add_photo_buffers(&photos, &options);
+        assert_eq!(summary.segments, 1);
+        // the line between the first two photos covers more than the three stamps
+        assert!(summary.delta.new_pixels > 3);
+
+        // a photo far away from the ones taken just before and after it
+        let photos = vec![
+            geotagged(31.2, 121.5, "2024:05:01 16:00:00"),
+            geotagged(31.5, 121.8, "2024:05:01 16:00:10"),
+            geotagged(31.2001, 121.5001, "2024:05:01 16:00:20"),
+        ];
+        let options = PhotoOptions {
+            filter: FilterOptions {
+                spikes: Some(SpikeOptions {
+                    max_duration: Duration::from_secs(60),
+                    min_distance: 1000.0,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let summary = FogMap::new().add_photo_buffers(&photos, &options);
+        assert_eq!(summary.points, 3);
+        assert_eq!(summary.dropped_points, 1);
+    }
+
+    #[cfg(all(feature = "native", unix))]
+    #[test]
+    fn test_add_photo_directory() {
+        let root = std::env::temp_dir().join(format!("fogcore-photos-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("2024/05")).unwrap();
+        let photo = geotagged(31.2, 121.5, "2024:05:01 16:00:00");
+        std::fs::write(root.join("2024/05/a.jpg"), photo).unwrap();
+        // a loop of links, the directory is only walked once
+        std::os::unix::fs::symlink("../..", root.join("2024/05/all")).unwrap();
+
+        let summary = FogMap::new()
+            .add_photo_directory(&root, &PhotoOptions::default())
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(summary.points, 1);
+    }
+}
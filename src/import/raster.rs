@@ -0,0 +1,237 @@
+//! Import of exploration data only kept as images, like screenshots of other apps or tiles
+//! rendered by `RenderedTrackMap`.
+//!
+//! The image is georeferenced in Web Mercator, so its pixels map linearly to the pixels of the
+//! [`FogMap`]. Each pixel of the map is taken from the pixel of the image covering its centre.
+
+use crate::fogmaps::PIXEL_MAP_WIDTH;
+#[cfg(feature = "native")]
+use crate::renderer::RenderResult;
+use crate::{ExplorationDelta, FogMap};
+use image::{DynamicImage, Rgba};
+
+/// The area covered by an image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Georeference {
+    /// The bounds of the image in degrees. A box crossing the antimeridian has `east < west`.
+    BBox {
+        west: f64,
+        north: f64,
+        east: f64,
+        south: f64,
+    },
+    /// A map tile, the image covering it entirely.
+    Tile { zoom: i16, x: i64, y: i64 },
+}
+
+#[cfg(feature = "native")]
+impl From<&RenderResult> for Georeference {
+    fn from(result: &RenderResult) -> Self {
+        Georeference::BBox {
+            west: result.left,
+            north: result.top,
+            east: result.right,
+            south: result.bottom,
+        }
+    }
+}
+
+impl Georeference {
+    // the bounds in pixels of the map, as (left, top, right, bottom)
+    fn pixel_bounds(&self) -> Result<(f64, f64, f64, f64), String> {
+        match *self {
+            Georeference::BBox {
+                west,
+                north,
+                east,
+                south,
+            } => {
+                if !(-90.0..=90.0).contains(&north)
+                    || !(-90.0..=90.0).contains(&south)
+                    || north <= south
+                    || !west.is_finite()
+                    || !east.is_finite()
+                    || west == east
+                {
+                    return Err(format!(
+                        "Invalid bounding box {}, {}, {}, {}",
+                        west, north, east, south
+                    ));
+                }
+                let (left, top) = FogMap::lng_lat_to_pixel_f64(west, north);
+                let (mut right, bottom) = FogMap::lng_lat_to_pixel_f64(east, south);
+                if right < left {
+                    right += PIXEL_MAP_WIDTH as f64;
+                }
+                Ok((left, top, right, bottom))
+            }
+            Georeference::Tile { zoom, x, y } => {
+                if !(0..=30).contains(&zoom)
+                    || !(0..1 << zoom).contains(&x)
+                    || !(0..1 << zoom).contains(&y)
+                {
+                    return Err(format!("Invalid tile {}/{}/{}", zoom, x, y));
+                }
+                let size = PIXEL_MAP_WIDTH as f64 / (1i64 << zoom) as f64;
+                let (x, y) = (x as f64 * size, y as f64 * size);
+                Ok((x, y, x + size, y + size))
+            }
+        }
+    }
+}
+
+/// Which pixels of the image are visited.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MaskRule {
+    /// Pixels with an alpha of at least this.
+    Opaque(u8),
+    /// Pixels with an alpha of at most this, like the explored areas of rendered tiles.
+    Transparent(u8),
+    /// Pixels within `tolerance` of `color` on every channel, alpha included. Grayscale images
+    /// are opaque, with the same value on every colour channel.
+    Color { color: Rgba<u8>, tolerance: u8 },
+}
+
+impl Default for MaskRule {
+    fn default() -> Self {
+        MaskRule::Opaque(128)
+    }
+}
+
+impl MaskRule {
+    fn is_visited(&self, pixel: &Rgba<u8>) -> bool {
+        match *self {
+            MaskRule::Opaque(min) => pixel[3] >= min,
+            MaskRule::Transparent(max) => pixel[3] <= max,
+            MaskRule::Color { color, tolerance } => pixel
+                .0
+                .iter()
+                .zip(color.0)
+                .all(|(&channel, expected)| channel.abs_diff(expected) <= tolerance),
+        }
+    }
+}
+
+// The first pixel of the map whose centre is at or after `position`.
+fn first_pixel(position: f64) -> i64 {
+    (position - 0.5).ceil() as i64
+}
+
+impl FogMap {
+    /// Marks as visited the pixels of the map covered by the visited pixels of an image.
+    pub fn add_raster_mask(
+        &mut self,
+        image: &DynamicImage,
+        georeference: &Georeference,
+        rule: &MaskRule,
+    ) -> Result<ExplorationDelta, String> {
+        let (left, top, right, bottom) = georeference.pixel_bounds()?;
+        let image = image.to_rgba8();
+        let mut delta = ExplorationDelta::new();
+        if image.width() == 0 || image.height() == 0 {
+            return Ok(delta);
+        }
+        let pixel_width = (right - left) / image.width() as f64;
+        let pixel_height = (bottom - top) / image.height() as f64;
+
+        for (row_index, row) in image.rows().enumerate() {
+            // the runs of visited pixels of the row, as map columns
+            let mut spans = Vec::new();
+            let mut start = None;
+            for (column, pixel) in row.chain(std::iter::once(&Rgba([0; 4]))).enumerate() {
+                let visited = column < image.width() as usize && rule.is_visited(pixel);
+                match (visited, start) {
+                    (true, None) => start = Some(column),
+                    (false, Some(first)) => {
+                        let x_start = first_pixel(left + first as f64 * pixel_width);
+                        let x_end = first_pixel(left + column as f64 * pixel_width) - 1;
+                        if x_end >= x_start {
+                            spans.push((x_start, x_end));
+                        }
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if spans.is_empty() {
+                continue;
+            }
+
+            let y_start = first_pixel(top + row_index as f64 * pixel_height).max(0);
+            let y_end = (first_pixel(top + (row_index + 1) as f64 * pixel_height) - 1)
+                .min(PIXEL_MAP_WIDTH - 1);
+            for y in y_start..=y_end {
+                for &(x_start, x_end) in &spans {
+                    self.fill_span(y, x_start, x_end, &mut delta);
+                }
+            }
+        }
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{TileRendererBasic, TileRendererTrait};
+    use crate::utils::{TileSize, DEFAULT_BG_COLOR2, DEFAULT_FG_COLOR2};
+    use image::RgbaImage;
+
+    #[test]
+    fn test_tile() {
+        let mut image = RgbaImage::new(4, 4);
+        image.put_pixel(1, 2, Rgba([255, 0, 0, 255]));
+        image.put_pixel(2, 2, Rgba([255, 0, 0, 100]));
+        let image = DynamicImage::ImageRgba8(image);
+        let georeference = Georeference::Tile {
+            zoom: 18,
+            x: 219_000,
+            y: 107_000,
+        };
+
+        // each pixel of the image is 4 by 4 pixels of the map
+        let mut fogmap = FogMap::new();
+        let delta = fogmap
+            .add_raster_mask(&image, &georeference, &MaskRule::default())
+            .unwrap();
+        assert_eq!(delta.new_pixels, 16);
+        let rule = MaskRule::Color {
+            color: Rgba([250, 0, 0, 90]),
+            tolerance: 10,
+        };
+        let delta = fogmap
+            .add_raster_mask(&image, &georeference, &rule)
+            .unwrap();
+        assert_eq!(delta.new_pixels, 16);
+        assert_eq!(delta.touched_tiles.len(), 1);
+
+        for (x, y) in [(0, 1 << 18), (1 << 18, 0), (-1, 0)] {
+            let invalid = Georeference::Tile { zoom: 18, x, y };
+            assert!(fogmap
+                .add_raster_mask(&image, &invalid, &MaskRule::default())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_rendered_tile() {
+        let mut fogmap = FogMap::new();
+        fogmap.add_circle(121.4737, 31.2304, 100.0);
+        let (x, y) = FogMap::lng_lat_to_tile_x_y(121.4737, 31.2304, 14);
+        let renderer = TileRendererBasic::new(TileSize::TileSize512);
+        let image = renderer.render_image(&fogmap, x, y, 14, DEFAULT_BG_COLOR2, DEFAULT_FG_COLOR2);
+
+        // the tile is rendered at a finer resolution than the map, so it comes back as it was
+        let mut imported = FogMap::new();
+        let delta = imported
+            .add_raster_mask(
+                &DynamicImage::ImageRgba8(image),
+                &Georeference::Tile { zoom: 14, x, y },
+                &MaskRule::Transparent(0),
+            )
+            .unwrap();
+        let mut original = FogMap::new();
+        let expected = original.add_circle(121.4737, 31.2304, 100.0).new_pixels;
+        assert!(delta.new_pixels.abs_diff(expected) * 50 < expected);
+    }
+}
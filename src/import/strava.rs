@@ -0,0 +1,261 @@
+//! Import of the bulk export of Strava and similar fitness platforms.
+//!
+//! The export is a zip archive whose `activities/` folder holds one file per activity, in GPX,
+//! TCX or FIT and possibly gzipped. The activity names are read from `activities.csv` when the
+//! archive has one.
+
+use super::filter::FilterOptions;
+use super::fit::FitOptions;
+use super::gpx::GpxOptions;
+use super::tcx::TcxOptions;
+use super::{GapOptions, ImportSummary};
+use crate::datum::Datum;
+use crate::FogMap;
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ActivityFormat {
+    Gpx,
+    Tcx,
+    Fit,
+}
+
+impl ActivityFormat {
+    /// Detects the format of a file from its name, and whether it is gzipped.
+    pub fn from_file_name(name: &str) -> Option<(Self, bool)> {
+        let name = name.to_lowercase();
+        let (name, gzipped) = match name.strip_suffix(".gz") {
+            Some(name) => (name, true),
+            None => (name.as_str(), false),
+        };
+        let format = match name.rsplit_once('.')?.1 {
+            "gpx" => Self::Gpx,
+            "tcx" => Self::Tcx,
+            "fit" => Self::Fit,
+            _ => return None,
+        };
+        Some((format, gzipped))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StravaOptions {
+    /// Where activities are split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the activities.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+/// The import of one activity of an export.
+#[derive(Debug, Clone)]
+pub struct ActivityReport {
+    /// Path of the activity file in the archive.
+    pub file_name: String,
+    /// Name of the activity, from `activities.csv`.
+    pub activity_name: Option<String>,
+    pub format: ActivityFormat,
+    pub result: Result<ImportSummary, String>,
+}
+
+// Maps the activity files to their names, from the `Filename` and `Activity Name` columns.
+fn read_activity_names<R: Read>(reader: R) -> Result<HashMap<String, String>, csv::Error> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name| headers.iter().position(|header| header == name);
+    let (Some(file_column), Some(name_column)) = (column("Filename"), column("Activity Name"))
+    else {
+        return Ok(HashMap::new());
+    };
+    let mut names = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        if let (Some(file), Some(name)) = (record.get(file_column), record.get(name_column)) {
+            if !file.is_empty() {
+                names.insert(file.to_string(), name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+impl FogMap {
+    /// Adds an activity file of the given format.
+    pub fn add_activity<R: Read>(
+        &mut self,
+        reader: R,
+        format: ActivityFormat,
+        options: &StravaOptions,
+    ) -> Result<ImportSummary, String> {
+        let StravaOptions {
+            gaps,
+            datum,
+            filter,
+        } = *options;
+        match format {
+            ActivityFormat::Gpx => self.add_gpx(
+                reader,
+                &GpxOptions {
+                    gaps,
+                    datum,
+                    filter,
+                    ..Default::default()
+                },
+            ),
+            ActivityFormat::Tcx => self.add_tcx(
+                reader,
+                &TcxOptions {
+                    gaps,
+                    datum,
+                    filter,
+                },
+            ),
+            ActivityFormat::Fit => self.add_fit(
+                reader,
+                &FitOptions {
+                    gaps,
+                    datum,
+                    filter,
+                },
+            ),
+        }
+    }
+
+    /// Adds all the activities of a bulk export archive. An activity that fails to import is
+    /// reported and does not stop the others.
+    pub fn add_strava_zip<R: Read + Seek>(
+        &mut self,
+        reader: R,
+        options: &StravaOptions,
+    ) -> Result<Vec<ActivityReport>, String> {
+        let mut archive = match zip::ZipArchive::new(reader) {
+            Ok(archive) => archive,
+            Err(e) => return Err(format!("Failed to read zip file: {}", e)),
+        };
+
+        let names = match archive.by_name("activities.csv") {
+            Ok(file) => read_activity_names(file).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+
+        let mut reports = Vec::new();
+        for i in 0..archive.len() {
+            let file_name = archive.name_for_index(i).unwrap_or_default().to_string();
+            let Some((format, gzipped)) = ActivityFormat::from_file_name(&file_name) else {
+                continue;
+            };
+            let file = match archive.by_index(i) {
+                Ok(file) => file,
+                Err(e) => {
+                    reports.push(ActivityReport {
+                        activity_name: names.get(&file_name).cloned(),
+                        file_name,
+                        format,
+                        result: Err(format!("Failed to read zip file: {}", e)),
+                    });
+                    continue;
+                }
+            };
+            if file.is_dir() {
+                continue;
+            }
+
+            let result = if gzipped {
+                self.add_activity(GzDecoder::new(file), format, options)
+            } else {
+                self.add_activity(file, format, options)
+            };
+            reports.push(ActivityReport {
+                activity_name: names.get(&file_name).cloned(),
+                file_name,
+                format,
+                result,
+            });
+        }
+        Ok(reports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::{Cursor, Write};
+
+    const GPX: &str = r#"<gpx><trk><trkseg>
+        <trkpt lat="31.2" lon="121.5"/><trkpt lat="31.201" lon="121.501"/>
+    </trkseg></trk></gpx>"#;
+
+    const TCX: &str = r#"<TrainingCenterDatabase><Activities><Activity><Lap><Track>
+        <Trackpoint><Position><LatitudeDegrees>31.3</LatitudeDegrees><LongitudeDegrees>121.6</LongitudeDegrees></Position></Trackpoint>
+        <Trackpoint><Position><LatitudeDegrees>31.301</LatitudeDegrees><LongitudeDegrees>121.601</LongitudeDegrees></Position></Trackpoint>
+    </Track></Lap></Activity></Activities></TrainingCenterDatabase>"#;
+
+    #[test]
+    fn test_add_strava_zip() {
+        let gzip = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            (
+                "activities.csv",
+                b"Activity ID,Activity Date,Activity Name,Filename\n\
+                  1,\"May 1, 2024\",Morning Ride,activities/1.gpx\n\
+                  2,\"May 2, 2024\",Evening Run,activities/2.tcx.gz\n"
+                    .to_vec(),
+            ),
+            ("activities/1.gpx", GPX.as_bytes().to_vec()),
+            ("activities/2.tcx.gz", gzip(TCX.as_bytes())),
+            ("activities/3.fit.gz", gzip(b"not a FIT file")),
+            ("media/photo.jpg", vec![0xFF, 0xD8]),
+        ];
+        let mut data = Vec::new();
+        {
+            let mut writer = zip::ZipWriter::new(Cursor::new(&mut data));
+            for (name, content) in files {
+                writer
+                    .start_file(name, zip::write::SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(&content).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        let mut fogmap = FogMap::new();
+        let reports = fogmap
+            .add_strava_zip(Cursor::new(&data), &StravaOptions::default())
+            .unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].activity_name.as_deref(), Some("Morning Ride"));
+        assert_eq!(reports[0].result.as_ref().unwrap().points, 2);
+        assert_eq!(reports[1].format, ActivityFormat::Tcx);
+        assert_eq!(reports[1].activity_name.as_deref(), Some("Evening Run"));
+        assert_eq!(reports[1].result.as_ref().unwrap().points, 2);
+        assert_eq!(reports[2].activity_name, None);
+        assert!(reports[2].result.is_err());
+        assert_eq!(fogmap.tiles.len(), 1);
+
+        // an entry that cannot be read does not stop the others, here it is compressed with
+        // an unsupported method
+        let gpx_entry = data
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| window == b"PK\x01\x02")
+            .map(|(i, _)| i)
+            .nth(1)
+            .unwrap();
+        data[gpx_entry + 10] = 12;
+        let reports = FogMap::new()
+            .add_strava_zip(Cursor::new(&data), &StravaOptions::default())
+            .unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].file_name, "activities/1.gpx");
+        assert!(reports[0].result.is_err());
+        assert!(reports[1].result.is_ok());
+    }
+}
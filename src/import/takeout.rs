@@ -0,0 +1,294 @@
+//! Import of Google location history.
+//!
+//! Two formats are supported:
+//! * `Records.json` from Google Takeout, a `locations` array of fixes with E7 coordinates.
+//! * The on-device Timeline export, whose `semanticSegments` hold `timelinePath`s. The older
+//!   Android variant, a bare array of segments with `point`s such as `geo:31.2,121.5` and
+//!   minute offsets from the segment start, is read as well.
+//!
+//! These files can reach gigabytes, so they are streamed: points are drawn as they are read and
+//! the document is never held in memory as a whole.
+
+use super::filter::FilterOptions;
+use super::{parse_time, GapOptions, ImportSummary, TrackPoint, TrackWriter};
+use crate::datum::Datum;
+use crate::FogMap;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{BufReader, Read};
+use std::marker::PhantomData;
+
+#[derive(Debug, Clone)]
+pub struct TakeoutOptions {
+    /// Where the history is split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the history.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+    /// Fixes with a larger accuracy radius, in meters, are dropped. Only `Records.json` has it.
+    pub max_accuracy: Option<f64>,
+}
+
+impl Default for TakeoutOptions {
+    fn default() -> Self {
+        Self {
+            gaps: GapOptions::default(),
+            datum: Datum::Wgs84,
+            filter: FilterOptions::default(),
+            max_accuracy: Some(100.0),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    latitude_e7: Option<i64>,
+    longitude_e7: Option<i64>,
+    accuracy: Option<f64>,
+    timestamp: Option<String>,
+    timestamp_ms: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineSegment {
+    start_time: Option<String>,
+    #[serde(default)]
+    timeline_path: Vec<TimelinePoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelinePoint {
+    point: String,
+    time: Option<String>,
+    duration_minutes_offset_from_start_time: Option<String>,
+}
+
+impl Record {
+    fn to_track_point(&self) -> Option<TrackPoint> {
+        // some exports store coordinates beyond the range of an i32 as overflowed values
+        let e7 = |value: i64| {
+            let value = if value > i32::MAX as i64 {
+                value - (1 << 32)
+            } else {
+                value
+            };
+            value as f64 / 1e7
+        };
+        let mut point = TrackPoint::new(e7(self.longitude_e7?), e7(self.latitude_e7?));
+        point.accuracy = self.accuracy;
+        point.time = match (&self.timestamp, &self.timestamp_ms) {
+            (Some(timestamp), _) => parse_time(timestamp),
+            (None, Some(timestamp_ms)) => timestamp_ms.parse().ok(),
+            (None, None) => None,
+        };
+        Some(point).filter(TrackPoint::is_valid)
+    }
+}
+
+impl TimelinePoint {
+    fn to_track_point(&self, start_time: Option<i64>) -> Option<TrackPoint> {
+        let (lat, lng) = parse_lat_lng(&self.point)?;
+        let mut point = TrackPoint::new(lng, lat);
+        point.time = match (&self.time, &self.duration_minutes_offset_from_start_time) {
+            (Some(time), _) => parse_time(time),
+            (None, Some(offset)) => Some(start_time? + offset.parse::<i64>().ok()? * 60_000),
+            (None, None) => None,
+        };
+        Some(point).filter(TrackPoint::is_valid)
+    }
+}
+
+// either `31.2000000°, 121.5000000°` or `geo:31.200000,121.500000`
+fn parse_lat_lng(text: &str) -> Option<(f64, f64)> {
+    let text = text.trim();
+    let text = text.strip_prefix("geo:").unwrap_or(text);
+    let (lat, lng) = text.split_once(',')?;
+    let parse = |value: &str| value.trim().trim_end_matches('°').parse::<f64>().ok();
+    Some((parse(lat)?, parse(lng)?))
+}
+
+// Calls a function on each element of an array, without collecting the array.
+struct ForEach<T, F>(F, PhantomData<T>);
+
+impl<T, F> ForEach<T, F> {
+    fn new(f: F) -> Self {
+        Self(f, PhantomData)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(T)> DeserializeSeed<'de> for ForEach<T, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(T)> Visitor<'de> for ForEach<T, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element()? {
+            (self.0)(element);
+        }
+        Ok(())
+    }
+}
+
+// The top level of either kind of export.
+struct TakeoutVisitor<'a, 'b> {
+    writer: &'a mut TrackWriter<'b>,
+    options: &'a TakeoutOptions,
+}
+
+impl TakeoutVisitor<'_, '_> {
+    fn add_record(&mut self, record: Record) {
+        let accurate = match (self.options.max_accuracy, record.accuracy) {
+            (Some(max_accuracy), Some(accuracy)) => accuracy <= max_accuracy,
+            _ => true,
+        };
+        match record.to_track_point() {
+            Some(point) if accurate => self.writer.push(point),
+            _ => self.writer.drop_point(),
+        }
+    }
+
+    fn add_segment(&mut self, segment: TimelineSegment) {
+        let start_time = segment.start_time.as_deref().and_then(parse_time);
+        for point in &segment.timeline_path {
+            match point.to_track_point(start_time) {
+                Some(point) => self.writer.push(point),
+                None => self.writer.drop_point(),
+            }
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for TakeoutVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a Google location history export")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "locations" => {
+                    map.next_value_seed(ForEach::new(|record| self.add_record(record)))?
+                }
+                "semanticSegments" => {
+                    map.next_value_seed(ForEach::new(|segment| self.add_segment(segment)))?
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, seq: A) -> Result<(), A::Error> {
+        ForEach::new(|segment| self.add_segment(segment)).visit_seq(seq)
+    }
+}
+
+impl FogMap {
+    /// Adds a Google location history, either a Takeout `Records.json` or an on-device
+    /// Timeline export.
+    pub fn add_google_takeout<R: Read>(
+        &mut self,
+        reader: R,
+        options: &TakeoutOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut writer = TrackWriter::new(self, options.gaps)
+            .with_filter(options.filter)
+            .with_datum(options.datum);
+        let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        deserializer
+            .deserialize_any(TakeoutVisitor {
+                writer: &mut writer,
+                options,
+            })
+            .and_then(|_| deserializer.end())
+            .map_err(|e| format!("Failed to read location history: {}", e))?;
+        Ok(writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let records = r#"{"locations": [
+            {"latitudeE7": 312000000, "longitudeE7": 1215000000, "accuracy": 10,
+             "timestamp": "2024-05-01T08:00:00.000Z", "activity": [{"type": "STILL"}]},
+            {"latitudeE7": 312010000, "longitudeE7": 1215010000, "accuracy": 5000,
+             "timestamp": "2024-05-01T08:00:30.000Z"},
+            {"latitudeE7": 312020000, "longitudeE7": 1215020000, "accuracy": 12,
+             "timestampMs": "1714550460000"},
+            {"latitudeE7": 312030000, "longitudeE7": 1215030000, "accuracy": 12,
+             "timestamp": "2024-05-01T12:00:00Z"},
+            {"latitudeE7": 312040000, "longitudeE7": 1215040000,
+             "timestamp": "2024-05-01T12:01:00Z"},
+            {"longitudeE7": 1215040000}
+        ]}"#;
+        let mut fogmap = FogMap::new();
+        let summary = fogmap
+            .add_google_takeout(records.as_bytes(), &TakeoutOptions::default())
+            .unwrap();
+        assert_eq!(summary.points, 6);
+        assert_eq!(summary.dropped_points, 2);
+        assert_eq!(summary.skipped_jumps, 1);
+        assert_eq!(summary.segments, 2);
+        assert!(!summary.delta.is_empty());
+
+        assert!(fogmap
+            .add_google_takeout(r#"{"locations": [{"#.as_bytes(), &Default::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let timeline = r#"{"semanticSegments": [
+            {"startTime": "2024-05-01T08:00:00.000+08:00", "endTime": "2024-05-01T10:00:00.000+08:00",
+             "timelinePath": [
+                {"point": "31.2000000°, 121.5000000°", "time": "2024-05-01T08:00:00.000+08:00"},
+                {"point": "31.2100000°, 121.5100000°", "time": "2024-05-01T08:02:00.000+08:00"}
+             ]},
+            {"startTime": "2024-05-01T10:00:00.000+08:00",
+             "visit": {"topCandidate": {"placeLocation": {"latLng": "31.2°, 121.5°"}}}},
+            {"startTime": "2024-05-01T10:00:00.000+08:00",
+             "timelinePath": [{"point": "31.2200000°, 121.5200000°", "time": "2024-05-01T08:04:00.000+08:00"}]}
+        ], "rawSignals": [], "userLocationProfile": {}}"#;
+        let summary = FogMap::new()
+            .add_google_takeout(timeline.as_bytes(), &TakeoutOptions::default())
+            .unwrap();
+        assert_eq!(summary.points, 3);
+        assert_eq!(summary.segments, 1);
+
+        let android = r#"[
+            {"startTime": "2024-05-01T08:00:00.000+08:00", "timelinePath": [
+                {"point": "geo:31.200000,121.500000", "durationMinutesOffsetFromStartTime": "0"},
+                {"point": "geo:31.210000,121.510000", "durationMinutesOffsetFromStartTime": "60"}
+            ]},
+            {"startTime": "2024-05-01T08:00:00.000+08:00", "activity": {}}
+        ]"#;
+        let summary = FogMap::new()
+            .add_google_takeout(android.as_bytes(), &TakeoutOptions::default())
+            .unwrap();
+        assert_eq!(summary.points, 2);
+        assert_eq!(summary.skipped_jumps, 1);
+    }
+}
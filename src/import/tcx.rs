@@ -0,0 +1,158 @@
+//! Import of Garmin Training Center XML (TCX) files.
+//!
+//! Each `Track` of an activity lap or of a course is a segment, its `Trackpoint`s without a
+//! `Position`, recorded while paused or indoors, are skipped.
+
+use super::filter::FilterOptions;
+use super::{parse_time, GapOptions, ImportSummary, TrackPoint};
+use crate::datum::Datum;
+use crate::FogMap;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::{BufReader, Read};
+
+#[derive(Debug, Clone, Default)]
+pub struct TcxOptions {
+    /// Where tracks are split instead of drawn.
+    pub gaps: GapOptions,
+    /// The datum of the coordinates in the file.
+    pub datum: Datum,
+    /// See [`FilterOptions`].
+    pub filter: FilterOptions,
+}
+
+/// Reads the tracks of a TCX file as segments.
+pub fn read_tcx<R: Read>(reader: R) -> Result<Vec<Vec<TrackPoint>>, String> {
+    let mut reader = Reader::from_reader(BufReader::new(reader));
+    reader.config_mut().trim_text(true);
+
+    let mut segments = Vec::new();
+    let mut segment: Option<Vec<TrackPoint>> = None;
+    // the latitude, longitude and time of the `Trackpoint` being read
+    let mut lat = None;
+    let mut lng = None;
+    let mut time = None;
+    let mut text = String::new();
+
+    let mut buf = Vec::new();
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("Failed to read TCX: {}", e))?
+        {
+            Event::Start(e) => {
+                match e.local_name().as_ref() {
+                    b"Track" => segment = Some(Vec::new()),
+                    b"Trackpoint" => (lat, lng, time) = (None, None, None),
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Text(e) => {
+                let decoded = e
+                    .decode()
+                    .map_err(|e| format!("Failed to read TCX: {}", e))?;
+                text.push_str(&decoded);
+            }
+            Event::End(e) => {
+                match e.local_name().as_ref() {
+                    b"LatitudeDegrees" => lat = text.trim().parse::<f64>().ok(),
+                    b"LongitudeDegrees" => lng = text.trim().parse::<f64>().ok(),
+                    b"Time" => time = parse_time(&text),
+                    b"Trackpoint" => {
+                        if let (Some(segment), Some(lat), Some(lng)) = (segment.as_mut(), lat, lng)
+                        {
+                            let point = TrackPoint {
+                                time,
+                                ..TrackPoint::new(lng, lat)
+                            };
+                            if point.is_valid() {
+                                segment.push(point);
+                            }
+                        }
+                    }
+                    b"Track" => segments.extend(segment.take()),
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(segments)
+}
+
+impl FogMap {
+    /// Adds the tracks of a TCX file.
+    pub fn add_tcx<R: Read>(
+        &mut self,
+        reader: R,
+        options: &TcxOptions,
+    ) -> Result<ImportSummary, String> {
+        let mut summary = ImportSummary::default();
+        for segment in read_tcx(reader)? {
+            let segment: Vec<_> = segment
+                .into_iter()
+                .map(|point| point.to_wgs84(options.datum))
+                .collect();
+            self.add_track(&segment, &options.gaps, &options.filter, &mut summary);
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strava writes TCX files with spaces before the XML declaration
+    const TCX: &str = r#"          <?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2">
+  <Activities>
+    <Activity Sport="Running">
+      <Id>2024-05-01T08:00:00Z</Id>
+      <Lap StartTime="2024-05-01T08:00:00Z">
+        <TotalTimeSeconds>20</TotalTimeSeconds>
+        <Track>
+          <Trackpoint>
+            <Time>2024-05-01T08:00:00Z</Time>
+            <Position><LatitudeDegrees>31.2</LatitudeDegrees><LongitudeDegrees>121.5</LongitudeDegrees></Position>
+            <HeartRateBpm><Value>120</Value></HeartRateBpm>
+          </Trackpoint>
+          <Trackpoint><Time>2024-05-01T08:00:05Z</Time></Trackpoint>
+          <Trackpoint>
+            <Time>2024-05-01T08:00:10Z</Time>
+            <Position><LatitudeDegrees>31.201</LatitudeDegrees><LongitudeDegrees>121.501</LongitudeDegrees></Position>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Lap StartTime="2024-05-01T08:00:20Z">
+        <Track>
+          <Trackpoint>
+            <Time>2024-05-01T08:00:20Z</Time>
+            <Position><LatitudeDegrees>31.202</LatitudeDegrees><LongitudeDegrees>121.502</LongitudeDegrees></Position>
+          </Trackpoint>
+        </Track>
+      </Lap>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>"#;
+
+    #[test]
+    fn test_read_tcx() {
+        let segments = read_tcx(TCX.as_bytes()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[0],
+            vec![
+                TrackPoint::with_time(121.5, 31.2, 1714550400000),
+                TrackPoint::with_time(121.501, 31.201, 1714550410000)
+            ]
+        );
+        assert_eq!(segments[1].len(), 1);
+
+        assert!(read_tcx("<Track><Trackpoint></Track>".as_bytes()).is_err());
+    }
+}
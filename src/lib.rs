@@ -5,7 +5,10 @@
 //! # Usage
 //! Please refer to the `examples` folder.
 
+pub mod datum;
+pub mod export;
 pub mod fogmaps;
+pub mod import;
 pub mod renderer;
 mod utils;
 
@@ -21,5 +24,5 @@ pub use renderer::TileRendererPremium2;
 pub use renderer::TileRendererTrait;
 pub use renderer::TileShader;
 
-pub use fogmaps::FogMap;
+pub use fogmaps::{ExplorationDelta, FogMap, LineMode};
 pub use utils::*;
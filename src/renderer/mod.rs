@@ -22,4 +22,4 @@ pub mod rendered_track_map;
 #[cfg(feature = "native")]
 pub use rendered_track_map::RenderedTrackMap;
 #[cfg(feature = "native")]
-pub use rendered_track_map::{BBox, Point};
+pub use rendered_track_map::{BBox, Point, RenderResult};
@@ -2,11 +2,11 @@ use std::f64::consts::PI;
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+use crate::log_print;
 use crate::FogMap;
 use image::{Rgba, RgbaImage};
 use std::fs::{self, File};
 use std::io::Cursor;
-use std::io::Read;
 use std::path::Path;
 
 pub const DEFAULT_BG_COLOR2: Rgba<u8> = Rgba([0, 0, 0, 127]);
@@ -99,11 +99,13 @@ pub fn load_tracks_map_folder(tiles_dir: &str) -> FogMap {
                         continue;
                     }
 
-                    let mut tile_file = File::open(&path).unwrap();
-                    let mut content = Vec::new();
-                    tile_file.read_to_end(&mut content).unwrap();
-                    println!("Loading file: {} with length: {}", file_name, content.len());
-                    fogmap.add_fow_file(file_name, content);
+                    log_print!("Loading file: {}", file_name);
+                    let result = File::open(&path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|tile_file| fogmap.add_fow_reader(file_name, tile_file));
+                    if let Err(e) = result {
+                        log_print!("Skipping file {}: {}", file_name, e);
+                    }
                 }
             }
         }
@@ -113,3 +115,13 @@ pub fn load_tracks_map_folder(tiles_dir: &str) -> FogMap {
 
     fogmap
 }
+
+/// Great-circle distance in meters between two locations, using the haversine formula.
+pub fn haversine_distance(lng1: f64, lat1: f64, lng2: f64, lat2: f64) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_008.8;
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
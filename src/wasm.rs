@@ -1,4 +1,5 @@
 use crate::fogmaps::FogMap as FogMapNative;
+use crate::import::photo::PhotoOptions;
 use crate::renderer::tile_shader2::TileShader2;
 use crate::renderer::TileRendererPremium2;
 use crate::utils::DEFAULT_TILE_SIZE;
@@ -60,6 +61,23 @@ impl FogMap {
         self.fogmap.add_fow_zip(data).unwrap();
     }
 
+    /// `photos` is an array of `Uint8Array`s. Photos taken within `connect_minutes` of each other
+    /// are joined, unless it is 0.
+    #[wasm_bindgen]
+    pub fn add_photos(&mut self, photos: js_sys::Array, radius: f64, connect_minutes: u32) {
+        let buffers: Vec<Vec<u8>> = photos
+            .iter()
+            .map(|photo| js_sys::Uint8Array::new(&photo).to_vec())
+            .collect();
+        let options = PhotoOptions {
+            radius,
+            connect_within: (connect_minutes > 0)
+                .then(|| std::time::Duration::from_secs(connect_minutes as u64 * 60)),
+            ..Default::default()
+        };
+        self.fogmap.add_photo_buffers(&buffers, &options);
+    }
+
     // TODO: use the correct zoom level
     #[wasm_bindgen]
     pub fn get_bounding_mercator_pixels(
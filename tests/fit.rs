@@ -0,0 +1,41 @@
+use fogcore::fogmaps::FogMap;
+use fogcore::import::fit::FitOptions;
+use std::fs::File;
+
+fn add_fit(path: &str) -> Result<fogcore::import::ImportSummary, String> {
+    FogMap::new().add_fit(File::open(path).unwrap(), &FitOptions::default())
+}
+
+#[test]
+fn activity() {
+    // two sessions, a record without a fix, then a chained big-endian FIT file
+    let summary = add_fit("tests/fit/activity.fit").unwrap();
+    assert_eq!(summary.points, 8);
+    assert_eq!(summary.dropped_points, 1);
+    assert_eq!(summary.segments, 3);
+    assert!(summary.errors.is_empty());
+    assert!(!summary.delta.is_empty());
+}
+
+#[test]
+fn truncated() {
+    // cut in the middle of the second record
+    let summary = add_fit("tests/fit/truncated.fit").unwrap();
+    assert_eq!(summary.points, 1);
+    assert_eq!(summary.errors.len(), 1);
+    assert!(summary.errors[0].starts_with("FIT file truncated"));
+}
+
+#[test]
+fn corrupt() {
+    let summary = add_fit("tests/fit/corrupt.fit").unwrap();
+    // the first record, then the chained file after the damaged one
+    assert_eq!(summary.points, 3);
+    assert_eq!(summary.errors.len(), 2);
+    assert!(summary.errors[0].starts_with("Message of undefined local type 5"));
+    assert_eq!(summary.errors[1], "FIT file CRC mismatch");
+
+    assert!(FogMap::new()
+        .add_fit("not a FIT file".as_bytes(), &FitOptions::default())
+        .is_err());
+}